@@ -0,0 +1,215 @@
+use std::{collections::HashMap, error::Error, fmt::{self, Display}, fs, io, path::{Path, PathBuf}, sync::Arc};
+
+use glam::{DVec2, Vec3, Vec4};
+
+use crate::texture::Filter;
+
+/// An image with colors from 0 to 1 and alpha in `w`, shared between textures through an [`Arc`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    /// the full image first, then each mip half the size of the one before down to a single pixel
+    levels: Vec<Level>,
+    opaque: bool,
+}
+impl Image {
+    /// `pixels` is row-major and has to hold `width * height` pixels, at least one
+    pub fn new(width: u32, height: u32, pixels: Vec<Vec4>) -> Self {
+        assert!(width > 0 && height > 0, "images can't be empty");
+        assert_eq!(pixels.len(), width as usize * height as usize, "wrong number of pixels for the size");
+        let opaque = pixels.iter().all(|pixel| pixel.w >= 1.0);
+        let mut levels = vec![Level { width, height, pixels }];
+        while let Some(level) = levels.last().filter(|level| level.width > 1 || level.height > 1) {
+            levels.push(level.half());
+        }
+        Self { levels, opaque }
+    }
+    pub fn width(&self) -> u32 {
+        self.levels[0].width
+    }
+    pub fn height(&self) -> u32 {
+        self.levels[0].height
+    }
+    pub fn pixel(&self, x: u32, y: u32) -> Vec4 {
+        self.levels[0].texel(x as i64, y as i64, false)
+    }
+    /// whether every pixel has full alpha
+    pub fn is_opaque(&self) -> bool {
+        self.opaque
+    }
+    /// The color at `uv`, where the image spans 0 to 1. Outside that it repeats if `wrap` and clamps to the edge otherwise.
+    /// `footprint` is how much of `uv` one screen pixel covers, picking the mip for [`Filter::Trilinear`].
+    pub fn sample(&self, uv: DVec2, wrap: bool, filter: Filter, footprint: DVec2) -> Vec4 {
+        match filter {
+            Filter::Nearest => self.levels[0].nearest(uv, wrap),
+            Filter::Bilinear => self.levels[0].bilinear(uv, wrap),
+            Filter::Trilinear => {
+                let texels = (footprint.abs() * DVec2::new(self.width() as f64, self.height() as f64)).max_element();
+                let lod = texels.log2().clamp(0.0, (self.levels.len() - 1) as f64);
+                let level = lod as usize;
+                let near = self.levels[level].bilinear(uv, wrap);
+                match self.levels.get(level + 1) {
+                    Some(next) => near.lerp(next.bilinear(uv, wrap), lod.fract() as f32),
+                    None => near,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Level {
+    width: u32,
+    height: u32,
+    /// row-major
+    pixels: Vec<Vec4>,
+}
+impl Level {
+    fn texel(&self, x: i64, y: i64, wrap: bool) -> Vec4 {
+        let (width, height) = (self.width as i64, self.height as i64);
+        let (x, y) = if wrap {
+            (x.rem_euclid(width), y.rem_euclid(height))
+        } else {
+            (x.clamp(0, width - 1), y.clamp(0, height - 1))
+        };
+        self.pixels[(x + y * width) as usize]
+    }
+    fn nearest(&self, uv: DVec2, wrap: bool) -> Vec4 {
+        let uv = if wrap { uv.rem_euclid(DVec2::ONE) } else { uv };
+        let coords = (uv * DVec2::new(self.width as f64, self.height as f64)).floor();
+        self.texel(coords.x as i64, coords.y as i64, wrap)
+    }
+    fn bilinear(&self, uv: DVec2, wrap: bool) -> Vec4 {
+        // texel centres are at half coordinates
+        let coords = uv * DVec2::new(self.width as f64, self.height as f64) - 0.5;
+        let floor = coords.floor();
+        let t = (coords - floor).as_vec2();
+        let (x, y) = (floor.x as i64, floor.y as i64);
+        let top = self.texel(x, y, wrap).lerp(self.texel(x + 1, y, wrap), t.x);
+        let bottom = self.texel(x, y + 1, wrap).lerp(self.texel(x + 1, y + 1, wrap), t.x);
+        top.lerp(bottom, t.y)
+    }
+    /// Half the size, averaging each 2x2 block weighted by alpha so transparent texels don't darken the edges.
+    fn half(&self) -> Level {
+        let width = self.width.div_ceil(2);
+        let height = self.height.div_ceil(2);
+        let pixels = (0..height as i64).flat_map(|y| (0..width as i64).map(move |x| (x, y))).map(|(x, y)| {
+            let block = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| self.texel(2 * x + dx, 2 * y + dy, false));
+            let alpha: f32 = block.iter().map(|texel| texel.w).sum();
+            let color = block.iter().map(|texel| texel.truncate() * texel.w).sum::<Vec3>();
+            if alpha > 0.0 {
+                (color / alpha).extend(alpha / 4.0)
+            } else {
+                Vec4::ZERO
+            }
+        }).collect();
+        Level { width, height, pixels }
+    }
+}
+
+#[derive(Debug)]
+pub enum AssetError {
+    Io(PathBuf, io::Error),
+    Decode(PathBuf, image::ImageError),
+    Empty(PathBuf),
+}
+impl Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetError::Io(path, err) => write!(f, "couldn't read {}: {err}", path.display()),
+            AssetError::Decode(path, err) => write!(f, "couldn't decode image {}: {err}", path.display()),
+            AssetError::Empty(path) => write!(f, "image {} has no pixels", path.display()),
+        }
+    }
+}
+impl Error for AssetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AssetError::Io(_, err) => Some(err),
+            AssetError::Decode(_, err) => Some(err),
+            AssetError::Empty(_) => None,
+        }
+    }
+}
+
+/// Loads PNG, TGA and BMP images once and hands out shared references to them.
+#[derive(Debug, Default)]
+pub struct Assets {
+    images: HashMap<PathBuf, Arc<Image>>,
+}
+impl Assets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The image at `path`, loading it if it isn't cached yet. PNG and BMP are recognised from their contents,
+    /// TGA has no signature so TGA files need a `.tga` extension.
+    pub fn image(&mut self, path: impl AsRef<Path>) -> Result<Arc<Image>, AssetError> {
+        let path = path.as_ref();
+        // different relative paths to the same file should share it
+        let key = fs::canonicalize(path).map_err(|err| AssetError::Io(path.to_owned(), err))?;
+        if let Some(image) = self.images.get(&key) {
+            return Ok(image.clone());
+        }
+        let image = Arc::new(load_image(path)?);
+        self.images.insert(key, image.clone());
+        Ok(image)
+    }
+    /// Caches an image made at runtime under a name, so later lookups of that name share it.
+    pub fn insert(&mut self, id: impl Into<PathBuf>, image: Image) -> Arc<Image> {
+        let image = Arc::new(image);
+        self.images.insert(id.into(), image.clone());
+        image
+    }
+    /// A previously inserted or loaded image, without loading anything.
+    pub fn get(&self, id: impl AsRef<Path>) -> Option<Arc<Image>> {
+        let id = id.as_ref();
+        self.images.get(id).or_else(|| self.images.get(&fs::canonicalize(id).ok()?)).cloned()
+    }
+}
+
+fn load_image(path: &Path) -> Result<Image, AssetError> {
+    let decoded = image::ImageReader::open(path)
+        .map_err(|err| AssetError::Io(path.to_owned(), err))?
+        .with_guessed_format()
+        .map_err(|err| AssetError::Io(path.to_owned(), err))?
+        .decode()
+        .map_err(|err| match err {
+            image::ImageError::IoError(err) => AssetError::Io(path.to_owned(), err),
+            err => AssetError::Decode(path.to_owned(), err),
+        })?
+        .into_rgba8();
+    if decoded.width() == 0 || decoded.height() == 0 {
+        return Err(AssetError::Empty(path.to_owned()));
+    }
+    let pixels = decoded.pixels().map(|pixel| Vec4::from_array(pixel.0.map(|x| x as f32 / 255.0))).collect();
+    Ok(Image::new(decoded.width(), decoded.height(), pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for the test to write images into, with an empty `sub` directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustray-{name}-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn paths_to_the_same_file_share_one_image() {
+        let dir = temp_dir("shared");
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 0, 0, 255])).save(dir.join("red.bmp")).unwrap();
+        let mut assets = Assets::new();
+        let direct = assets.image(dir.join("red.bmp")).unwrap();
+        let roundabout = assets.image(dir.join("sub/../red.bmp")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(Arc::ptr_eq(&direct, &roundabout));
+        assert_eq!(direct.pixel(1, 1), Vec4::new(1.0, 0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn missing_files_are_io_errors() {
+        let result = Assets::new().image("no/such/image.png");
+        assert!(matches!(result, Err(AssetError::Io(..))), "{result:?}");
+    }
+}
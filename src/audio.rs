@@ -1,83 +1,272 @@
-use std::{f64::consts::PI, sync::{Arc, Mutex}};
+use std::{f64::consts::TAU, fs::File, io::{self, BufWriter, Write}, path::Path, sync::{Arc, Mutex}};
 
-use rand::{rng, Rng};
+use glam::DVec2;
+use rand::Rng;
 use sdl3::audio::AudioCallback;
 
+use crate::{camera::{Camera, Ray}, random::{GameRng, RngContext}, scene::Scene, synth::{Channel, Patch, Placement, Synth, SynthHandle, VoiceId, WaveType}};
+
+/// output is interleaved stereo, left first
+pub const CHANNELS: usize = 2;
+
 #[derive(Clone, Copy, Debug)]
 pub struct AudioData {
     pub white_noise: f32,
+    /// radiation dose at the listener, drives the geiger counter
+    pub dose: f64,
 }
 
-#[derive(Clone, Debug)]
+/// waves of the drone that gets louder with the static
+const HUM: [(WaveType, f32); 4] = [(WaveType::Saw, 0.5), (WaveType::Sine, 0.5), (WaveType::Square, 0.5), (WaveType::WhiteNoise, 0.5)];
+
+#[derive(Debug)]
 pub struct AudioHandler {
     data: Arc<Mutex<AudioData>>,
     buffer: Vec<f32>,
     samples: usize,
-    sample: usize,
+    synth: Synth,
+    hum: [VoiceId; HUM.len()],
+    geiger: Geiger,
 }
 impl AudioHandler {
-    pub fn new(data: Arc<Mutex<AudioData>>, samples: usize) -> Self {
-        Self { data, buffer: Vec::with_capacity(128), samples, sample: 0 }
+    /// The handler and a handle for playing sounds on it from the game.
+    pub fn new(data: Arc<Mutex<AudioData>>, samples: usize, rng: &RngContext) -> (Self, SynthHandle) {
+        let (mut synth, handle) = Synth::new(rng.stream(0));
+        let hum = HUM.map(|(wave, _)| synth.note_on(Patch { channel: Channel::Ambience, ..Patch::new(wave) }, 440.0, 0.0, Placement::CENTER));
+        (Self { data, buffer: Vec::with_capacity(128), samples, synth, hum, geiger: Geiger::new(rng.stream(1)) }, handle)
+    }
+    /// Renders the next `frames` frames of interleaved samples, without an audio device.
+    /// Commands sent to the synth so far take effect at the start.
+    pub fn render(&mut self, data: AudioData, frames: usize) -> &[f32] {
+        self.synth.update();
+        for (&id, (_, volume)) in self.hum.iter().zip(HUM) {
+            self.synth.set_volume(id, volume * data.white_noise);
+        }
+        let dt = 1.0 / self.samples as f64;
+        self.buffer.clear();
+        for _ in 0..frames {
+            let click = self.geiger.next(data.dose, dt);
+            let [left, right] = self.synth.next(dt);
+            self.buffer.extend([left + click, right + click]);
+        }
+        &self.buffer
+    }
+    /// Renders `seconds` of audio where each entry of the timeline sets the data from its time in seconds on.
+    /// No hum or clicks until the first entry. `update` is called with the block's length in seconds before each block,
+    /// to drive the synth the way the game thread would, e.g. with [`Soundscape::update`].
+    pub fn render_timeline(&mut self, timeline: &[(f64, AudioData)], seconds: f64, mut update: impl FnMut(f64)) -> Vec<f32> {
+        // the same block size a device would ask for, so commands land at the same granularity
+        const BLOCK: usize = 512;
+        let total = (seconds * self.samples as f64) as usize;
+        let mut samples = Vec::with_capacity(total * CHANNELS);
+        let mut data = AudioData { white_noise: 0.0, dose: 0.0 };
+        let mut next = timeline.iter().peekable();
+        let mut frame = 0;
+        while frame < total {
+            while let Some(&(_, entry)) = next.next_if(|(time, _)| (time * self.samples as f64) as usize <= frame) {
+                data = entry;
+            }
+            let until = next.peek().map_or(total, |(time, _)| ((time * self.samples as f64) as usize).min(total));
+            let frames = (until - frame).min(BLOCK);
+            update(frames as f64 / self.samples as f64);
+            samples.extend_from_slice(self.render(data, frames));
+            frame += frames;
+        }
+        samples
     }
 }
 impl AudioCallback<f32> for AudioHandler {
     fn callback(&mut self, stream: &mut sdl3::audio::AudioStream, requested: i32) {
-        let mut t = self.sample as f64 / self.samples as f64;
-        let mut rng = rng();
         let data_lock = self.data.lock().unwrap();
         let data = *data_lock;
         drop(data_lock);
-        self.buffer.clear();
-        for _ in 0..(requested as usize) {
-            t += 1.0 / self.samples as f64;
-            let white_noise = rng.random_range(-1.0..=1.0) * 0.5 * data.white_noise;
-            let noise = (Wave::new(WaveType::Saw, 440.0, 0.5).get(t) + Wave::new(WaveType::Sine, 440.0, 0.5).get(t) + Wave::new(WaveType::Square, 440.0, 0.5).get(t)) as f32 * data.white_noise;
-            self.buffer.push(white_noise + noise);
-        }
-
-        stream.put_data_f32(&self.buffer).unwrap();
-        self.sample += requested as usize;
+        // requested counts samples over all channels
+        let samples = self.render(data, requested as usize / CHANNELS);
+        stream.put_data_f32(samples).unwrap();
     }
 }
 
+/// Writes interleaved samples as a 16 bit wav file.
+pub fn save_wav(path: impl AsRef<Path>, samples: &[f32], sample_rate: usize) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let channels = CHANNELS as u16;
+    let bytes_per_frame = channels as u32 * 2;
+    let data_len = samples.len() as u32 * 2;
+    file.write_all(b"RIFF")?;
+    file.write_all(&(36 + data_len).to_le_bytes())?;
+    file.write_all(b"WAVEfmt ")?;
+    file.write_all(&16u32.to_le_bytes())?;
+    // integer pcm
+    file.write_all(&1u16.to_le_bytes())?;
+    file.write_all(&channels.to_le_bytes())?;
+    file.write_all(&(sample_rate as u32).to_le_bytes())?;
+    file.write_all(&(sample_rate as u32 * bytes_per_frame).to_le_bytes())?;
+    file.write_all(&(bytes_per_frame as u16).to_le_bytes())?;
+    file.write_all(&16u16.to_le_bytes())?;
+    file.write_all(b"data")?;
+    file.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        file.write_all(&((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())?;
+    }
+    file.flush()
+}
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Wave {
-    ty: WaveType,
-    hz: f64,
-    volume: f64,
+/// Keeps the scene's emitters playing, placed relative to the camera. Lives on the game thread.
+pub struct Soundscape {
+    voices: Vec<EmitterVoice>,
+}
+enum EmitterVoice {
+    Held(VoiceId),
+    /// seconds until the next note
+    Repeating(f64),
 }
-impl Wave {
-    pub fn get(&self, t: f64) -> f64 {
-        self.ty.get(self.hz, t) * self.volume
+impl Soundscape {
+    /// how much quieter emitters are behind a wall
+    const OCCLUDED_VOLUME: f32 = 0.5;
+
+    pub fn new(scene: &Scene, synth: &SynthHandle) -> Self {
+        let voices = scene.emitters.iter().map(|emitter| match emitter.interval {
+            None => EmitterVoice::Held(synth.note_on(emitter.patch, emitter.hz, 0.0, Placement::CENTER)),
+            Some(_) => EmitterVoice::Repeating(0.0),
+        }).collect();
+        Self { voices }
+    }
+    pub fn update(&mut self, scene: &Scene, camera: &Camera, synth: &SynthHandle, dt: f64) {
+        for (emitter, voice) in scene.emitters.iter().zip(&mut self.voices) {
+            let (volume, placement) = hear(scene, camera, emitter.pos);
+            match voice {
+                EmitterVoice::Held(id) => {
+                    synth.set_volume(*id, emitter.volume * volume);
+                    synth.set_placement(*id, placement);
+                }
+                EmitterVoice::Repeating(wait) => {
+                    let interval = emitter.interval.unwrap_or_default();
+                    *wait -= dt;
+                    if *wait <= 0.0 {
+                        *wait = (*wait + interval).max(0.0);
+                        synth.play(emitter.patch, emitter.hz, emitter.volume * volume, interval / 2.0, placement);
+                    }
+                }
+            }
+        }
     }
-    pub fn new(ty: WaveType, hz: f64, volume: f64) -> Self {
-        Self { ty, hz, volume }
+}
+
+/// How loud a sound at `pos` is for the camera and where it's heard from.
+fn hear(scene: &Scene, camera: &Camera, pos: DVec2) -> (f32, Placement) {
+    let offset = pos - camera.pos;
+    let dist = offset.length();
+    // amplitude falls off with distance, a unit away and closer is full volume
+    let mut volume = 1.0 / dist.max(1.0) as f32;
+    let pan = if dist > 0.0 { DVec2::from_angle(camera.rot).perp_dot(offset) / dist } else { 0.0 } as f32;
+    let occluded = scene.sample(&Ray { origin: camera.pos, dir: offset }).is_some_and(|hit| hit.dist < 1.0);
+    if occluded {
+        volume *= Soundscape::OCCLUDED_VOLUME;
     }
+    (volume, Placement { pan, muffle: if occluded { 1.0 } else { 0.0 } })
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum WaveType {
-    Sine,
-    Square,
-    Saw
+/// A geiger counter's clicks, a Poisson process whose rate follows the dose.
+#[derive(Clone, Debug)]
+pub struct Geiger {
+    rng: GameRng,
+    /// expected number of counts since the last click, a click happens when it reaches `threshold`
+    count: f64,
+    /// exponentially distributed, so the clicks stay a Poisson process even while the rate changes
+    threshold: f64,
+    /// seconds until the tube can count again
+    dead: f64,
+    /// seconds since the last click
+    age: f64,
+    loudness: f32,
 }
-impl WaveType {
-    fn get(&self, hz: f64, t: f64) -> f64 {
-        match self {
-            WaveType::Sine => (hz * t * 2.0 * PI).sin(),
-            WaveType::Square => {
-                let scaled_t = (hz * t).floor();
-                if scaled_t % 2.0 == 0.0 {
-                    -1.0
-                } else {
-                    1.0
-                }
-            },
-            WaveType::Saw => {
-                let scaled_t = (2.0 * hz * t) % 2.0;
-                scaled_t - 1.0
+impl Geiger {
+    /// clicks per second at a dose of 1
+    pub const COUNTS_PER_DOSE: f64 = 20.0;
+    /// the tube ignores anything this soon after a click, so the rate saturates at its inverse
+    pub const DEAD_TIME: f64 = 1.5e-3;
+    /// ringing frequency of the speaker
+    const CLICK_HZ: f64 = 3300.0;
+    /// time for a click to decay by a factor of e
+    const CLICK_DECAY: f64 = 4e-4;
+    const CLICK_LENGTH: f64 = 8.0 * Self::CLICK_DECAY;
+    const VOLUME: f32 = 0.5;
+
+    pub fn new(mut rng: GameRng) -> Self {
+        let threshold = exponential(&mut rng);
+        Self { rng, count: 0.0, threshold, dead: 0.0, age: Self::CLICK_LENGTH, loudness: 0.0 }
+    }
+    /// Advances by `dt` seconds and returns the next sample.
+    pub fn next(&mut self, dose: f64, dt: f64) -> f32 {
+        self.age += dt;
+        if self.dead > 0.0 {
+            self.dead -= dt;
+        } else {
+            self.count += dose.max(0.0) * Self::COUNTS_PER_DOSE * dt;
+            if self.count >= self.threshold {
+                self.count = 0.0;
+                self.threshold = exponential(&mut self.rng);
+                self.dead = Self::DEAD_TIME;
+                self.age = 0.0;
+                self.loudness = self.rng.random_range(0.7..=1.0);
             }
         }
+        if self.age >= Self::CLICK_LENGTH {
+            return 0.0;
+        }
+        // a sharp broadband snap followed by the speaker ringing out
+        let envelope = (-self.age / Self::CLICK_DECAY).exp();
+        let ring = (TAU * Self::CLICK_HZ * self.age).sin();
+        let snap = self.rng.random_range(-1.0..=1.0) * envelope;
+        ((0.6 * ring + 0.4 * snap) * envelope) as f32 * self.loudness * Self::VOLUME
+    }
+}
+
+/// exponentially distributed with a mean of 1
+fn exponential(rng: &mut GameRng) -> f64 {
+    -(1.0 - rng.random::<f64>()).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{camera::Fog, scene::{tests::segment, Emitter}};
+
+    const SAMPLE_RATE: usize = 44100;
+
+    fn render(seed: u64) -> Vec<f32> {
+        let data = AudioData { white_noise: 0.3, dose: 2.0 };
+        let (mut handler, _) = AudioHandler::new(Arc::new(Mutex::new(data)), SAMPLE_RATE, &RngContext::new(seed));
+        handler.render(data, SAMPLE_RATE / 2).to_vec()
+    }
+
+    #[test]
+    fn render_is_reproducible_from_the_seed() {
+        assert_eq!(render(5), render(5));
+        assert_ne!(render(5), render(6));
     }
-}
\ No newline at end of file
+
+    /// a second of standing between a hum and a beeping emitter behind a wall, with the dose rising halfway through
+    fn render_scene(seed: u64) -> Vec<f32> {
+        let mut scene = Scene::new(vec![segment(DVec2::new(3.0, -1.0), DVec2::new(3.0, 1.0))]);
+        let emitter = |pos, wave, hz, interval| Emitter { pos, patch: Patch::new(wave), hz, volume: 0.3, interval };
+        scene.emitters.push(emitter(DVec2::new(-1.0, 0.0), WaveType::Saw, 55.0, None));
+        scene.emitters.push(emitter(DVec2::new(5.0, 0.0), WaveType::Sine, 1400.0, Some(0.3)));
+        let camera = Camera { pos: DVec2::ZERO, height: Camera::EYE_HEIGHT, rot: 0.0, fov: 66f64.to_radians(), noise: 0.0, fog: Fog::default() };
+        let quiet = AudioData { white_noise: 0.0, dose: 0.0 };
+        let (mut handler, synth) = AudioHandler::new(Arc::new(Mutex::new(quiet)), SAMPLE_RATE, &RngContext::new(seed));
+        let mut soundscape = Soundscape::new(&scene, &synth);
+        let timeline = [(0.0, quiet), (0.5, AudioData { white_noise: 0.3, dose: 5.0 })];
+        handler.render_timeline(&timeline, 1.0, |dt| soundscape.update(&scene, &camera, &synth, dt))
+    }
+
+    #[test]
+    fn scene_audio_is_reproducible_and_follows_the_timeline() {
+        let samples = render_scene(9);
+        assert_eq!(samples.len(), SAMPLE_RATE * CHANNELS);
+        assert_eq!(samples, render_scene(9));
+        let loudness = |samples: &[f32]| samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32;
+        let (before, after) = samples.split_at(samples.len() / 2);
+        assert!(loudness(after) > loudness(before), "the dose rising should be heard");
+    }
+}
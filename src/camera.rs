@@ -1,4 +1,5 @@
-use glam::DVec2;
+use glam::{DVec2, Vec3};
+use serde::Deserialize;
 
 #[derive(Clone, Copy)]
 pub struct Ray {
@@ -8,12 +9,17 @@ pub struct Ray {
 
 pub struct Camera {
     pub pos: DVec2,
+    /// eye height above the world's zero, not above the floor
+    pub height: f64,
     pub rot: f64,
     pub fov: f64,
     pub noise: f64,
-    pub fog_dist: f64,
+    pub fog: Fog,
 }
 impl Camera {
+    /// eye height above the floor
+    pub const EYE_HEIGHT: f64 = 0.5;
+
     pub fn get_rays(&self, n: usize) -> impl Iterator<Item = Ray> {
         (0..n).map(move |x| {
             let r = x as f64 / (n-1) as f64;
@@ -28,3 +34,65 @@ impl Camera {
         (pos - self.pos).project_onto(DVec2::from_angle(self.rot)).length()
     }
 }
+
+/// Haze between the camera and everything it sees, thickening with distance.
+/// Distances are perpendicular to the view like everything else the renderer measures,
+/// so walls, floors and sprites at the same depth fade the same and `max_dist` cuts them off together.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fog {
+    #[serde(default)]
+    pub falloff: Falloff,
+    /// what things fade into, black if not given
+    #[serde(default)]
+    pub color: Vec3,
+    /// nothing further away is drawn, whatever rays don't reach before it shows the fog's color
+    #[serde(default = "default_max_dist")]
+    pub max_dist: f64,
+}
+fn default_max_dist() -> f64 {
+    f64::INFINITY
+}
+impl Default for Fog {
+    fn default() -> Self {
+        Self { falloff: Falloff::default(), color: Vec3::ZERO, max_dist: default_max_dist() }
+    }
+}
+impl Fog {
+    /// How much of a surface `dist` away shows through, 1 up close down to 0.
+    pub fn visibility(&self, dist: f64) -> f32 {
+        let visibility = match self.falloff {
+            Falloff::Inverse(clear) => clear / dist,
+            Falloff::Linear(start, end) => (end - dist) / (end - start).max(1e-9),
+            Falloff::Exp(density) => (-density * dist).exp(),
+            Falloff::Exp2(density) => (-(density * dist).powi(2)).exp(),
+        };
+        if dist >= self.max_dist {
+            0.0
+        } else {
+            visibility.clamp(0.0, 1.0) as f32
+        }
+    }
+    /// `color` as seen from `dist` away.
+    pub fn apply(&self, color: Vec3, dist: f64) -> Vec3 {
+        self.color.lerp(color, self.visibility(dist))
+    }
+}
+
+/// How quickly [`Fog`] thickens.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Falloff {
+    /// clear up to the distance, then fading with its inverse so it never quite hides anything
+    Inverse(f64),
+    /// clear up to the first distance and opaque from the second
+    Linear(f64, f64),
+    /// the same fraction of light is lost over every unit, the density
+    Exp(f64),
+    /// clear for a while and then quickly thick, like smoke
+    Exp2(f64),
+}
+impl Default for Falloff {
+    fn default() -> Self {
+        Falloff::Inverse(1.5)
+    }
+}
@@ -0,0 +1,241 @@
+use glam::DVec2;
+
+use crate::scene::{Scene, Segment};
+
+/// how many times a move can be redirected along a wall before giving up on the rest of it
+const MAX_SLIDES: usize = 4;
+/// gap kept between the collider and walls, so the next sweep doesn't start touching one
+const SKIN: f64 = 1e-4;
+
+/// A vertical cylinder standing on the floor that walls push around.
+#[derive(Debug, Clone, Copy)]
+pub struct Collider {
+    pub radius: f64,
+    pub height: f64,
+    /// walls whose top is at most this far above the floor are stepped over
+    pub step_height: f64,
+}
+
+pub struct Collision<'a> {
+    /// where the collider's centre was when it touched the wall
+    pub pos: DVec2,
+    /// points away from the wall, towards the collider
+    pub normal: DVec2,
+    pub segment: &'a Segment,
+}
+
+pub struct Movement<'a> {
+    pub pos: DVec2,
+    /// every wall touched on the way, in order
+    pub collisions: Vec<Collision<'a>>,
+}
+
+impl Collider {
+    /// Moves from `pos` by `motion`, sliding along any walls in the way instead of stopping at them.
+    pub fn move_and_slide<'a>(&self, scene: &'a Scene, pos: DVec2, motion: DVec2) -> Movement<'a> {
+        let mut pos = self.depenetrate(scene, pos);
+        let mut remaining = motion;
+        let mut collisions: Vec<Collision> = Vec::new();
+        for _ in 0..MAX_SLIDES {
+            if remaining == DVec2::ZERO {
+                break;
+            }
+            let Some((t, normal, segment)) = self.sweep(scene, pos, remaining) else {
+                pos += remaining;
+                break;
+            };
+            pos += remaining * t + normal * SKIN;
+            remaining *= 1.0 - t;
+            remaining -= normal * remaining.dot(normal).min(0.0);
+            // wedged between walls facing each other, any slide would go back into one of them
+            if collisions.iter().any(|collision| remaining.dot(collision.normal) < 0.0) {
+                remaining = DVec2::ZERO;
+            }
+            collisions.push(Collision { pos, normal, segment });
+        }
+        Movement { pos: self.depenetrate(scene, pos), collisions }
+    }
+
+    /// Whether the segment is in the way of a collider standing on `floor`.
+    pub fn blocked_by(&self, segment: &Segment, floor: f64) -> bool {
+        segment.top > floor + self.step_height && segment.bottom < floor + self.height
+    }
+
+    /// Segments that block the collider anywhere in the box.
+    fn nearby<'a>(&self, scene: &'a Scene, min: DVec2, max: DVec2, floor: f64) -> impl Iterator<Item = &'a Segment> {
+        let margin = self.radius + SKIN;
+        scene.segments_near(min - margin, max + margin).filter(move |segment| self.blocked_by(segment, floor))
+    }
+
+    /// The earliest fraction of `motion` at which the circle touches a wall, with the wall's normal.
+    fn sweep<'a>(&self, scene: &'a Scene, pos: DVec2, motion: DVec2) -> Option<(f64, DVec2, &'a Segment)> {
+        let floor = scene.floor_at(pos);
+        let end = pos + motion;
+        self.nearby(scene, pos.min(end), pos.max(end), floor)
+            .filter_map(|segment| sweep_segment(pos, motion, self.radius, segment).map(|(t, normal)| (t, normal, segment)))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    }
+
+    /// Pushes the circle out of any walls it overlaps, e.g. after spawning inside one.
+    fn depenetrate(&self, scene: &Scene, mut pos: DVec2) -> DVec2 {
+        let floor = scene.floor_at(pos);
+        for _ in 0..MAX_SLIDES {
+            let mut moved = false;
+            for segment in self.nearby(scene, pos, pos, floor) {
+                let closest = closest_point(segment, pos);
+                let offset = pos - closest;
+                let dist = offset.length();
+                if dist >= self.radius {
+                    continue;
+                }
+                let normal = if dist > 0.0 {
+                    offset / dist
+                } else {
+                    (segment.b - segment.a).perp().normalize_or_zero()
+                };
+                pos = closest + normal * (self.radius + SKIN);
+                moved = true;
+            }
+            if !moved {
+                break;
+            }
+        }
+        pos
+    }
+}
+
+fn closest_point(segment: &Segment, pos: DVec2) -> DVec2 {
+    let d = segment.b - segment.a;
+    let t = ((pos - segment.a).dot(d) / d.length_squared()).clamp(0.0, 1.0);
+    if t.is_nan() {
+        segment.a
+    } else {
+        segment.a + d * t
+    }
+}
+
+/// When a circle moving by `motion` first touches the segment, as a fraction of `motion`.
+fn sweep_segment(pos: DVec2, motion: DVec2, radius: f64, segment: &Segment) -> Option<(f64, DVec2)> {
+    let d = segment.b - segment.a;
+    let mut best: Option<(f64, DVec2)> = None;
+    let mut consider = |t: f64, normal: DVec2| {
+        if (0.0..=1.0).contains(&t) && best.is_none_or(|(best, _)| t < best) {
+            best = Some((t, normal));
+        }
+    };
+
+    // the flat side facing the circle
+    let mut normal = d.perp().normalize_or_zero();
+    let side = (pos - segment.a).dot(normal);
+    if side < 0.0 {
+        normal = -normal;
+    }
+    let approach = -motion.dot(normal);
+    if approach > 0.0 {
+        // already touching counts as hitting straight away, so pushing off one wall can't tunnel into another
+        let t = ((side.abs() - radius) / approach).max(0.0);
+        let along = (pos + motion * t - segment.a).dot(d) / d.length_squared();
+        if (0.0..=1.0).contains(&along) {
+            consider(t, normal);
+        }
+    }
+
+    // the rounded ends
+    for end in [segment.a, segment.b] {
+        let offset = pos - end;
+        let b = offset.dot(motion);
+        if b >= 0.0 {
+            continue;
+        }
+        let a = motion.length_squared();
+        let c = offset.length_squared() - radius * radius;
+        let discriminant = b * b - a * c;
+        if discriminant < 0.0 {
+            continue;
+        }
+        let t = ((-b - discriminant.sqrt()) / a).max(0.0);
+        consider(t, (offset + motion * t).normalize_or_zero());
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::tests::segment;
+
+    const COLLIDER: Collider = Collider { radius: 0.1, height: 0.6, step_height: 0.2 };
+
+    /// a closed polygon of walls
+    fn walls(corners: &[DVec2]) -> Vec<Segment> {
+        (0..corners.len()).map(|i| segment(corners[i], corners[(i + 1) % corners.len()])).collect()
+    }
+
+    /// Panics if the collider at `pos` overlaps any wall.
+    fn assert_clear(scene: &Scene, pos: DVec2) {
+        for segment in scene.segments() {
+            let dist = (pos - closest_point(segment, pos)).length();
+            assert!(dist >= COLLIDER.radius, "{pos} is {dist} from {:?}-{:?}", segment.a, segment.b);
+        }
+    }
+
+    #[test]
+    fn slides_along_a_wall() {
+        let scene = Scene::new(vec![segment(DVec2::new(1.0, -10.0), DVec2::new(1.0, 10.0))]);
+        let movement = COLLIDER.move_and_slide(&scene, DVec2::ZERO, DVec2::new(2.0, 1.0));
+        assert!((0.899..0.9).contains(&movement.pos.x), "{}", movement.pos);
+        assert!((movement.pos.y - 1.0).abs() < 1e-9, "{}", movement.pos);
+        assert_eq!(movement.collisions.len(), 1);
+    }
+
+    #[test]
+    fn stays_out_of_an_inside_corner() {
+        let scene = Scene::new(vec![segment(DVec2::new(1.0, -10.0), DVec2::new(1.0, 1.0)), segment(DVec2::new(-10.0, 1.0), DVec2::new(1.0, 1.0))]);
+        let mut pos = DVec2::ZERO;
+        for _ in 0..1000 {
+            pos = COLLIDER.move_and_slide(&scene, pos, DVec2::splat(0.05)).pos;
+            assert_clear(&scene, pos);
+        }
+        assert!(pos.x < 0.9 && pos.y < 0.9, "{pos}");
+    }
+
+    #[test]
+    fn slides_around_an_outside_corner() {
+        let scene = Scene::new(walls(&[DVec2::new(1.0, 1.0), DVec2::new(2.0, 1.0), DVec2::new(2.0, 2.0), DVec2::new(1.0, 2.0)]));
+        let mut pos = DVec2::new(0.0, 0.5);
+        for _ in 0..200 {
+            pos = COLLIDER.move_and_slide(&scene, pos, DVec2::new(0.03, 0.01)).pos;
+            assert_clear(&scene, pos);
+        }
+        // slid along the bottom of the block and out past it rather than getting stuck
+        assert!(pos.x > 2.1, "{pos}");
+    }
+
+    #[test]
+    fn fast_moves_dont_tunnel_through_thin_walls() {
+        let scene = Scene::new(vec![segment(DVec2::new(1.0, -1.0), DVec2::new(1.0, 1.0))]);
+        let movement = COLLIDER.move_and_slide(&scene, DVec2::ZERO, DVec2::new(100.0, 0.0));
+        assert!(movement.pos.x < 0.9, "{}", movement.pos);
+    }
+
+    #[test]
+    fn stops_short_of_an_acute_wedges_tip() {
+        let tip = DVec2::new(5.0, 0.0);
+        let scene = Scene::new(vec![segment(DVec2::new(0.0, 1.0), tip), segment(DVec2::new(0.0, -1.0), tip)]);
+        let mut pos = DVec2::new(0.5, 0.1);
+        for _ in 0..1000 {
+            pos = COLLIDER.move_and_slide(&scene, pos, DVec2::new(0.05, 0.0)).pos;
+            assert_clear(&scene, pos);
+        }
+        assert!(pos.x < tip.x, "{pos}");
+    }
+
+    #[test]
+    fn degenerate_walls_and_moves_dont_panic() {
+        let scene = Scene::new(vec![segment(DVec2::ONE, DVec2::ONE), segment(DVec2::new(1.0, -1.0), DVec2::new(1.0, 1.0))]);
+        for motion in [DVec2::ZERO, DVec2::new(0.0, 1.0), DVec2::new(2.0, 0.0), DVec2::new(1.0, 1.0)] {
+            let movement = COLLIDER.move_and_slide(&scene, DVec2::new(0.9, 0.0), motion);
+            assert!(movement.pos.is_finite());
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use glam::{DVec2, IVec2};
+
+use crate::{camera::Ray, scene::Segment};
+
+/// most cells along either axis, so huge sparse levels don't allocate huge grids
+const MAX_CELLS_PER_AXIS: usize = 1024;
+/// cells are widened by this much when inserting so rays grazing a cell boundary still find their segment
+const EPSILON: f64 = 1e-6;
+
+/// Uniform grid over the segments of a scene, storing segment indices per cell.
+#[derive(Default)]
+pub struct Grid {
+    min: DVec2,
+    cell_size: f64,
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<u32>>,
+}
+impl Grid {
+    pub fn new(segments: &[Segment]) -> Self {
+        if segments.is_empty() {
+            return Self::default();
+        }
+        let (min, max) = segments.iter().fold((DVec2::INFINITY, DVec2::NEG_INFINITY), |(min, max), segment| {
+            (min.min(segment.a).min(segment.b), max.max(segment.a).max(segment.b))
+        });
+        let min = min - EPSILON;
+        let extent = max + EPSILON - min;
+
+        // aim for about two cells per segment
+        let target_cells = (segments.len() * 2) as f64;
+        let cell_size = (extent.x * extent.y / target_cells).sqrt().max(extent.max_element() / MAX_CELLS_PER_AXIS as f64);
+        let cols = ((extent.x / cell_size).ceil() as usize).clamp(1, MAX_CELLS_PER_AXIS);
+        let rows = ((extent.y / cell_size).ceil() as usize).clamp(1, MAX_CELLS_PER_AXIS);
+
+        let mut grid = Self { min, cell_size, cols, rows, cells: vec![Vec::new(); cols * rows] };
+        for (i, segment) in segments.iter().enumerate() {
+            grid.insert(i as u32, segment);
+        }
+        grid
+    }
+
+    /// Adds the segment to every cell it touches, row by row.
+    fn insert(&mut self, index: u32, segment: &Segment) {
+        let d = segment.b - segment.a;
+        let first_row = self.row_of(segment.a.y.min(segment.b.y) - EPSILON);
+        let last_row = self.row_of(segment.a.y.max(segment.b.y) + EPSILON);
+        for row in first_row..=last_row {
+            // clip the segment to this row's band
+            let y0 = self.min.y + row as f64 * self.cell_size - EPSILON;
+            let y1 = y0 + self.cell_size + 2.0 * EPSILON;
+            let (x0, x1) = if d.y == 0.0 {
+                (segment.a.x.min(segment.b.x), segment.a.x.max(segment.b.x))
+            } else {
+                let t0 = ((y0 - segment.a.y) / d.y).clamp(0.0, 1.0);
+                let t1 = ((y1 - segment.a.y) / d.y).clamp(0.0, 1.0);
+                let (x0, x1) = (segment.a.x + d.x * t0, segment.a.x + d.x * t1);
+                (x0.min(x1), x0.max(x1))
+            };
+            for col in self.col_of(x0 - EPSILON)..=self.col_of(x1 + EPSILON) {
+                self.cells[col + row * self.cols].push(index);
+            }
+        }
+    }
+    /// Indices of the segments in every cell overlapping the box, without duplicates.
+    pub fn query(&self, min: DVec2, max: DVec2) -> Vec<u32> {
+        let mut indices = Vec::new();
+        self.query_into(min, max, usize::MAX, &mut indices);
+        indices
+    }
+    /// [`Grid::query`] appending to `indices`, or leaving them alone and returning false if the box covers more than `max_cells` cells.
+    pub fn query_into(&self, min: DVec2, max: DVec2, max_cells: usize, indices: &mut Vec<u32>) -> bool {
+        if self.cells.is_empty() {
+            return true;
+        }
+        let (cols, rows) = (self.col_of(min.x)..=self.col_of(max.x), self.row_of(min.y)..=self.row_of(max.y));
+        if cols.clone().count().saturating_mul(rows.clone().count()) > max_cells {
+            return false;
+        }
+        let start = indices.len();
+        for row in rows {
+            for col in cols.clone() {
+                indices.extend_from_slice(&self.cells[col + row * self.cols]);
+            }
+        }
+        indices[start..].sort_unstable();
+        // `Vec::dedup` would also merge with whatever was in `indices` before
+        let mut unique = start;
+        for i in start..indices.len() {
+            if unique == start || indices[i] != indices[unique - 1] {
+                indices[unique] = indices[i];
+                unique += 1;
+            }
+        }
+        indices.truncate(unique);
+        true
+    }
+    fn col_of(&self, x: f64) -> usize {
+        (((x - self.min.x) / self.cell_size).floor().max(0.0) as usize).min(self.cols - 1)
+    }
+    fn row_of(&self, y: f64) -> usize {
+        (((y - self.min.y) / self.cell_size).floor().max(0.0) as usize).min(self.rows - 1)
+    }
+
+    /// Walks the cells along the ray in order (Amanatides & Woo DDA), yielding the segment indices
+    /// in each cell with the ray distances at which the ray enters and leaves that cell.
+    ///
+    /// Distances are in units of `ray.dir`, like [`crate::scene::HitData::dist`].
+    pub fn traverse(&self, ray: &Ray) -> Traversal<'_> {
+        let mut traversal = Traversal {
+            grid: self,
+            cell: IVec2::ZERO,
+            step: IVec2::ZERO,
+            t_delta: DVec2::ZERO,
+            t_max: DVec2::ZERO,
+            t: 0.0,
+            t_leave: 0.0,
+            done: true,
+        };
+        if self.cells.is_empty() || ray.dir == DVec2::ZERO {
+            return traversal;
+        }
+        let max = self.min + DVec2::new(self.cols as f64, self.rows as f64) * self.cell_size;
+
+        // clip the ray to the grid bounds
+        let inv_dir = ray.dir.recip();
+        let t0 = (self.min - ray.origin) * inv_dir;
+        let t1 = (max - ray.origin) * inv_dir;
+        let t_enter = t0.min(t1).max_element().max(0.0);
+        let t_leave = t0.max(t1).min_element();
+        if t_enter > t_leave {
+            return traversal;
+        }
+
+        let start = ray.origin + ray.dir * t_enter;
+        let cell = IVec2::new(self.col_of(start.x) as i32, self.row_of(start.y) as i32);
+        let step = IVec2::new(ray.dir.x.signum() as i32, ray.dir.y.signum() as i32);
+        let next_boundary = self.min + (cell.as_dvec2() + step.max(IVec2::ZERO).as_dvec2()) * self.cell_size;
+        traversal.cell = cell;
+        traversal.step = step;
+        traversal.t_delta = (self.cell_size * inv_dir).abs();
+        traversal.t_max = DVec2::new(
+            if ray.dir.x == 0.0 { f64::INFINITY } else { (next_boundary.x - ray.origin.x) * inv_dir.x },
+            if ray.dir.y == 0.0 { f64::INFINITY } else { (next_boundary.y - ray.origin.y) * inv_dir.y },
+        );
+        traversal.t = t_enter;
+        traversal.t_leave = t_leave;
+        traversal.done = false;
+        traversal
+    }
+}
+
+/// Iterator returned by [`Grid::traverse`].
+pub struct Traversal<'a> {
+    grid: &'a Grid,
+    cell: IVec2,
+    step: IVec2,
+    t_delta: DVec2,
+    t_max: DVec2,
+    /// where the ray enters the current cell
+    t: f64,
+    t_leave: f64,
+    done: bool,
+}
+impl<'a> Iterator for Traversal<'a> {
+    type Item = (&'a [u32], f64, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let grid = self.grid;
+        let enter = self.t;
+        let exit = self.t_max.min_element();
+        let cell = &grid.cells[self.cell.x as usize + self.cell.y as usize * grid.cols];
+
+        if exit >= self.t_leave {
+            self.done = true;
+        } else {
+            if self.t_max.x < self.t_max.y {
+                self.cell.x += self.step.x;
+                self.t_max.x += self.t_delta.x;
+            } else {
+                self.cell.y += self.step.y;
+                self.t_max.y += self.t_delta.y;
+            }
+            let cell = self.cell;
+            self.done = cell.x < 0 || cell.y < 0 || cell.x as usize >= grid.cols || cell.y as usize >= grid.rows;
+        }
+        self.t = exit;
+        Some((cell, enter, exit))
+    }
+}
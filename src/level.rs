@@ -0,0 +1,357 @@
+//! Level files, written in [RON](https://github.com/ron-rs/ron).
+//!
+//! ```ron
+//! (
+//!     camera: (pos: (24.5, 0.0), rot: 180.0, fog: (falloff: Exp2(0.2), color: (0.05, 0.1, 0.05), max_dist: 12.0)),
+//!     segments: [
+//!         (a: (0.0, -0.5), b: (0.0, 0.5), texture: Compound(Glitch(0.5), Stretch("eyes.bmp"), Multiply)),
+//!         (a: (8.0, -0.5), b: (8.0, 0.5), opacity: 0.3, texture: Solid((0.6, 0.8, 1.0))),
+//!         (a: (8.5, -0.5), b: (8.5, 0.5), texture: Mask(Repeat("tiles.bmp"), Compound(Repeat("tiles.bmp"), Solid((0.3, 0.25, 0.1)), Lerp(0.7)), Pattern(Worley(cell: 0.4)))),
+//!         (a: (9.0, -0.5), b: (9.0, 0.5), texture: Pulse(Scroll(Repeat("tiles.bmp"), (0.0, 0.5)), (0.0, 0.0, 0.0), 0.25)),
+//!         // the sides, tall enough for the raised ceiling
+//!         (a: (0.0, 0.5), b: (25.0, 0.5), top: 1.5, texture: Repeat("tiles.bmp")),
+//!         (a: (0.0, -0.5), b: (25.0, -0.5), top: 1.5, texture: Repeat("tiles.bmp")),
+//!         // the step's risers and the lintels above it, where the areas start and end
+//!         (a: (10.0, -0.5), b: (10.0, 0.5), top: 0.25, texture: Repeat("tiles.bmp")),
+//!         (a: (10.0, -0.5), b: (10.0, 0.5), bottom: 1.0, top: 1.5, texture: Repeat("tiles.bmp")),
+//!         (a: (14.0, -0.5), b: (14.0, 0.5), top: 0.25, texture: Repeat("tiles.bmp")),
+//!         (a: (14.0, -0.5), b: (14.0, 0.5), bottom: 1.0, top: 1.5, texture: Repeat("tiles.bmp")),
+//!         (a: (18.0, -0.5), b: (18.0, 0.5), top: 0.0, texture: Solid((0.0, 0.0, 0.0))),
+//!     ],
+//!     areas: [
+//!         (min: (10.0, -0.5), max: (14.0, 0.5), floor: 0.25, ceiling: 1.5, floor_texture: Some(Repeat("tiles.bmp"))),
+//!         (min: (14.0, -0.5), max: (18.0, 0.5), floor_texture: Some(Compound(
+//!             Pattern(Brick(brick: (0.4, 0.15, 0.1), mortar: (0.5, 0.5, 0.45))),
+//!             Pattern(Perlin(cell: 0.3, octaves: 3)),
+//!             Multiply,
+//!         ))),
+//!     ],
+//!     floor_texture: Some(Solid((0.1, 0.1, 0.1))),
+//!     sprites: [
+//!         (pos: (5.0, 0.0), size: 0.5, texture: Cutout(Stretch("eyes.bmp"), (0.251, 0.251, 0.251))),
+//!     ],
+//!     sources: [
+//!         (pos: (0.1, 0.0), strength: 20.0),
+//!     ],
+//!     ambient: (0.3, 0.3, 0.3),
+//!     lights: [
+//!         (pos: (0.1, 0.0), height: 0.2, color: (0.3, 1.0, 0.2), intensity: 2.0, radius: 6.0),
+//!         (pos: (20.0, 0.4), color: (1.0, 0.9, 0.7), spot: Some((rot: 180.0, inner: 15.0, outer: 30.0))),
+//!     ],
+//!     emitters: [
+//!         (pos: (0.5, 0.0), hz: 55.0, volume: 0.2, sound: (wave: Saw, tremolo: Some((hz: 6.0, depth: 0.3)))),
+//!         (pos: (12.0, 0.3), hz: 1400.0, interval: Some(1.3), sound: (
+//!             wave: Sine,
+//!             envelope: (attack: 0.002, decay: 0.15, sustain: 0.0, release: 0.05),
+//!             vibrato: Some((hz: 12.0, depth: 4.0)),
+//!         )),
+//!     ],
+//! )
+//! ```
+//!
+//! Image paths are relative to the level file and can be PNG, TGA or BMP, each file is only loaded once. Angles are in degrees.
+//! Segments span heights 0 to 1 and areas have their floor at 0 and ceiling at 1 unless given.
+//! Floors and ceilings only change where a segment is hit, so area edges need one, even a flat one like at 18 above.
+//! Segments stop half of the radiation passing through them unless given a `shielding` between 0 and 1.
+//! `Pattern` textures are generated rather than loaded, and are laid out in world units along the surface unless given `Uv`.
+//! `Flipbook`, `Scroll` and `Pulse` textures and noise patterns with a `rate` animate with the game clock, in seconds.
+//! Fog fades to black as the inverse of the distance from 1.5 units on unless given.
+//! Everything is lit by the `ambient` light, white unless given, plus any lights not shadowed by walls.
+//! Lights sit half way up unless given a `height` and fade out by their `radius`, 8 unless given.
+//! Floor and ceiling textures are sampled in world space, tiling once per unit, and default to a checkerboard.
+
+use std::{error::Error, fmt::{self, Display}, fs, io, path::{Path, PathBuf}};
+
+use glam::{DVec2, Vec3};
+use serde::Deserialize;
+
+use crate::{assets::{AssetError, Assets}, camera::{Camera, Fog}, scene::{Area, Emitter, Light, RadiationSource, Scene, Segment, Spot, Sprite}, synth::{Channel, Envelope, Fm, Lfo, Patch, WaveType}, texture::{BlendMode, Pattern, Space, Texture}};
+
+#[derive(Debug)]
+pub enum LevelError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, Box<ron::error::SpannedError>),
+    Asset(AssetError),
+}
+impl Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelError::Io(path, err) => write!(f, "couldn't read level {}: {err}", path.display()),
+            LevelError::Parse(path, err) => write!(f, "{}:{err}", path.display()),
+            LevelError::Asset(err) => write!(f, "{err}"),
+        }
+    }
+}
+impl Error for LevelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LevelError::Io(_, err) => Some(err),
+            LevelError::Parse(_, err) => Some(err.as_ref()),
+            LevelError::Asset(err) => Some(err),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LevelDesc {
+    camera: CameraDesc,
+    segments: Vec<SegmentDesc>,
+    #[serde(default)]
+    sprites: Vec<SpriteDesc>,
+    #[serde(default)]
+    sources: Vec<SourceDesc>,
+    #[serde(default)]
+    lights: Vec<LightDesc>,
+    #[serde(default = "default_ambient")]
+    ambient: Vec3,
+    #[serde(default)]
+    emitters: Vec<EmitterDesc>,
+    #[serde(default)]
+    areas: Vec<AreaDesc>,
+    floor_texture: Option<TextureDesc>,
+    ceiling_texture: Option<TextureDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    pos: DVec2,
+    #[serde(default)]
+    rot: f64,
+    #[serde(default = "default_fov")]
+    fov: f64,
+    #[serde(default)]
+    noise: f64,
+    #[serde(default)]
+    fog: Fog,
+}
+fn default_fov() -> f64 {
+    66.0
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SegmentDesc {
+    a: DVec2,
+    b: DVec2,
+    #[serde(default = "default_floor")]
+    bottom: f64,
+    #[serde(default = "default_ceiling")]
+    top: f64,
+    #[serde(default = "default_opacity")]
+    opacity: f32,
+    #[serde(default = "default_shielding")]
+    shielding: f64,
+    texture: TextureDesc,
+}
+fn default_opacity() -> f32 {
+    1.0
+}
+fn default_shielding() -> f64 {
+    0.5
+}
+fn default_floor() -> f64 {
+    Scene::FLOOR
+}
+fn default_ceiling() -> f64 {
+    Scene::CEILING
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AreaDesc {
+    min: DVec2,
+    max: DVec2,
+    #[serde(default = "default_floor")]
+    floor: f64,
+    #[serde(default = "default_ceiling")]
+    ceiling: f64,
+    floor_texture: Option<TextureDesc>,
+    ceiling_texture: Option<TextureDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpriteDesc {
+    pos: DVec2,
+    #[serde(default = "default_sprite_size")]
+    size: f64,
+    texture: TextureDesc,
+}
+fn default_sprite_size() -> f64 {
+    0.5
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceDesc {
+    pos: DVec2,
+    strength: f64,
+}
+
+fn default_ambient() -> Vec3 {
+    Vec3::ONE
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightDesc {
+    pos: DVec2,
+    #[serde(default = "default_light_height")]
+    height: f64,
+    #[serde(default = "default_light_color")]
+    color: Vec3,
+    #[serde(default = "default_intensity")]
+    intensity: f32,
+    #[serde(default = "default_radius")]
+    radius: f64,
+    spot: Option<SpotDesc>,
+}
+fn default_light_height() -> f64 {
+    0.5
+}
+fn default_light_color() -> Vec3 {
+    Vec3::ONE
+}
+fn default_intensity() -> f32 {
+    1.0
+}
+fn default_radius() -> f64 {
+    8.0
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpotDesc {
+    rot: f64,
+    #[serde(default)]
+    inner: f64,
+    outer: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmitterDesc {
+    pos: DVec2,
+    hz: f64,
+    #[serde(default = "default_volume")]
+    volume: f32,
+    interval: Option<f64>,
+    sound: PatchDesc,
+}
+fn default_volume() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatchDesc {
+    wave: WaveType,
+    #[serde(default = "default_envelope")]
+    envelope: Envelope,
+    fm: Option<Fm>,
+    vibrato: Option<Lfo>,
+    tremolo: Option<Lfo>,
+}
+fn default_envelope() -> Envelope {
+    Envelope::GATE
+}
+
+#[derive(Deserialize)]
+enum TextureDesc {
+    Solid(Vec3),
+    Stretch(PathBuf),
+    Repeat(PathBuf),
+    Glitch(f64),
+    Compound(Box<TextureDesc>, Box<TextureDesc>, #[serde(default)] BlendMode),
+    Mask(Box<TextureDesc>, Box<TextureDesc>, Box<TextureDesc>),
+    Cutout(Box<TextureDesc>, Vec3),
+    Pattern(Pattern, #[serde(default)] Space),
+    Flipbook(Vec<TextureDesc>, f64),
+    Scroll(Box<TextureDesc>, DVec2),
+    Pulse(Box<TextureDesc>, Vec3, f64),
+}
+impl TextureDesc {
+    fn build(self, dir: &Path, assets: &mut Assets) -> Result<Texture, LevelError> {
+        let mut open = |path: PathBuf| assets.image(dir.join(path)).map_err(LevelError::Asset);
+        Ok(match self {
+            TextureDesc::Solid(color) => Texture::Solid(color),
+            TextureDesc::Stretch(path) => Texture::Stretch(open(path)?),
+            TextureDesc::Repeat(path) => Texture::Repeat(open(path)?),
+            TextureDesc::Glitch(amount) => Texture::Glitch(amount),
+            TextureDesc::Compound(a, b, blend) => Texture::Compound(Box::new(a.build(dir, assets)?), Box::new(b.build(dir, assets)?), blend),
+            TextureDesc::Mask(a, b, mask) => Texture::Mask(Box::new(a.build(dir, assets)?), Box::new(b.build(dir, assets)?), Box::new(mask.build(dir, assets)?)),
+            TextureDesc::Cutout(texture, key) => Texture::Cutout(Box::new(texture.build(dir, assets)?), key),
+            TextureDesc::Pattern(pattern, space) => Texture::Pattern(pattern, space),
+            TextureDesc::Flipbook(frames, fps) => Texture::Flipbook(frames.into_iter().map(|frame| frame.build(dir, assets)).collect::<Result<_, _>>()?, fps),
+            TextureDesc::Scroll(texture, velocity) => Texture::Scroll(Box::new(texture.build(dir, assets)?), velocity),
+            TextureDesc::Pulse(texture, color, hz) => Texture::Pulse(Box::new(texture.build(dir, assets)?), color, hz),
+        })
+    }
+}
+
+/// Loads a level, taking its images from `assets` so they're shared with anything else loaded through it.
+pub fn load(path: &Path, assets: &mut Assets) -> Result<(Scene, Camera), LevelError> {
+    let source = fs::read_to_string(path).map_err(|err| LevelError::Io(path.to_owned(), err))?;
+    let desc: LevelDesc = ron::from_str(&source).map_err(|err| LevelError::Parse(path.to_owned(), Box::new(err)))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    let segments = desc.segments.into_iter()
+        .map(|segment| Ok(Segment { a: segment.a, b: segment.b, bottom: segment.bottom, top: segment.top, opacity: segment.opacity, shielding: segment.shielding, texture: segment.texture.build(dir, assets)? }))
+        .collect::<Result<_, LevelError>>()?;
+    let sprites = desc.sprites.into_iter()
+        .map(|sprite| Ok(Sprite { pos: sprite.pos, size: sprite.size, texture: sprite.texture.build(dir, assets)? }))
+        .collect::<Result<_, LevelError>>()?;
+    let mut scene = Scene::new(segments);
+    scene.sprites = sprites;
+    scene.emitters = desc.emitters.into_iter().map(|emitter| Emitter {
+        pos: emitter.pos,
+        patch: Patch {
+            wave: emitter.sound.wave,
+            envelope: emitter.sound.envelope,
+            fm: emitter.sound.fm,
+            vibrato: emitter.sound.vibrato,
+            tremolo: emitter.sound.tremolo,
+            channel: Channel::Ambience,
+        },
+        hz: emitter.hz,
+        volume: emitter.volume,
+        interval: emitter.interval,
+    }).collect();
+    scene.lights = desc.lights.into_iter().map(|light| Light {
+        pos: light.pos,
+        height: light.height,
+        color: light.color,
+        intensity: light.intensity,
+        radius: light.radius,
+        spot: light.spot.map(|spot| Spot { rot: spot.rot.to_radians(), inner: spot.inner.to_radians(), outer: spot.outer.to_radians() }),
+    }).collect();
+    scene.ambient = desc.ambient;
+    scene.sources = desc.sources.into_iter().map(|source| RadiationSource { pos: source.pos, strength: source.strength }).collect();
+    scene.areas = desc.areas.into_iter()
+        .map(|area| Ok(Area {
+            min: area.min,
+            max: area.max,
+            floor: area.floor,
+            ceiling: area.ceiling,
+            floor_texture: area.floor_texture.map(|texture| texture.build(dir, assets)).transpose()?,
+            ceiling_texture: area.ceiling_texture.map(|texture| texture.build(dir, assets)).transpose()?,
+        }))
+        .collect::<Result<_, LevelError>>()?;
+    if let Some(texture) = desc.floor_texture {
+        scene.floor_texture = texture.build(dir, assets)?;
+    }
+    if let Some(texture) = desc.ceiling_texture {
+        scene.ceiling_texture = texture.build(dir, assets)?;
+    }
+    let camera = Camera {
+        pos: desc.camera.pos,
+        height: scene.floor_at(desc.camera.pos) + Camera::EYE_HEIGHT,
+        rot: desc.camera.rot.to_radians(),
+        fov: desc.camera.fov.to_radians(),
+        noise: desc.camera.noise,
+        fog: desc.camera.fog,
+    };
+    Ok((scene, camera))
+}
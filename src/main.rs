@@ -2,25 +2,138 @@ use std::f64::consts::{FRAC_PI_2, PI};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use audio::{AudioData, AudioHandler};
-use camera::{Camera, Ray};
+use audio::{AudioData, AudioHandler, Soundscape};
+use random::RngContext;
+use camera::Camera;
+use collision::Collider;
 use glam::DVec2;
-use renderer::Renderer;
-use scene::{Scene, Segment};
+use post::{Bloom, ChromaticAberration, Curvature, Grain, PostChain, Response, Scanlines, Vignette};
+use renderer::{Persistence, Renderer};
+use scene::Scene;
 use sdl3::audio::{AudioFormat, AudioSpec};
 use sdl3::event::Event;
 use sdl3::keyboard::{Keycode, Scancode};
-use texture::{BlendMode, Texture};
+use synth::{Envelope, Fm, Patch, Placement, WaveType};
 
 mod renderer;
 pub mod scene;
 pub mod camera;
 pub mod texture;
-mod audio;
+pub mod level;
+pub mod assets;
+pub mod random;
+pub mod collision;
+pub mod synth;
+mod grid;
+mod noise;
+pub mod audio;
+pub mod post;
 
 const FPS: usize = 60;
 
+const PLAYER: Collider = Collider { radius: 0.1, height: 0.6, step_height: 0.2 };
+
+/// played when walking into a wall
+const BUMP: Patch = Patch {
+    envelope: Envelope { attack: 0.002, decay: 0.08, sustain: 0.0, release: 0.02 },
+    fm: Some(Fm { ratio: 1.4, index: 2.0 }),
+    ..Patch::new(WaveType::Sine)
+};
+
+const RENDER_RNG: u64 = 0;
+const AUDIO_RNG: u64 = 1;
+const POST_RNG: u64 = 2;
+
+const SAMPLE_RATE: usize = 44100;
+
+/// How much static a radiation dose causes, never quite clean or fully white.
+fn noise_from_dose(dose: f64) -> f64 {
+    (1.0 - (-dose).exp()).clamp(0.3, 0.998)
+}
+
+fn audio_for_dose(dose: f64) -> AudioData {
+    AudioData { white_noise: (noise_from_dose(dose) - 0.2) as f32 / 3.0, dose }
+}
+
+/// The screen wearing out as the dose rises, clean without any.
+fn post_chain() -> PostChain {
+    let mut chain = PostChain::new();
+    chain.push(Bloom { threshold: 0.6, radius: 4, intensity: 0.8 }, Response { base: 0.0, rate: 0.5 });
+    chain.push(ChromaticAberration { offset: 0.02 }, Response { base: 0.0, rate: 0.4 });
+    chain.push(Scanlines { darkness: 0.5, period: 2.0 }, Response { base: 0.0, rate: 0.6 });
+    chain.push(Curvature { amount: 0.15 }, Response { base: 0.0, rate: 0.3 });
+    chain.push(Vignette { amount: 0.8 }, Response { base: 0.0, rate: 0.5 });
+    chain.push(Grain { amount: 0.15 }, Response { base: 0.0, rate: 0.3 });
+    chain
+}
+
 fn main() {
+    let width = 480;
+    let height = 270;
+    let mut level = "./level.ron".to_owned();
+    let mut headless = None;
+    let mut wav = None;
+    let mut seconds = 5.0;
+    let mut seed = None;
+    let mut threads = None;
+    let mut filter = None;
+    let mut persistence = Persistence::default();
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--level" => level = args.next().expect("--level needs a path"),
+            // the paths are optional, so a flag straight after one isn't taken for it
+            "--headless" => headless = Some(args.next_if(|x| !x.starts_with("--")).unwrap_or_else(|| "frame.bmp".to_owned())),
+            "--wav" => wav = Some(args.next_if(|x| !x.starts_with("--")).unwrap_or_else(|| "audio.wav".to_owned())),
+            "--seconds" => seconds = args.next().and_then(|x| x.parse().ok()).expect("--seconds needs a number"),
+            "--seed" => seed = Some(args.next().and_then(|x| x.parse().ok()).expect("--seed needs a number")),
+            "--filter" => filter = Some(args.next().and_then(|x| x.parse().ok()).expect("--filter needs nearest, bilinear or trilinear")),
+            "--ghosting" => persistence.ghosting = args.next().and_then(|x| x.parse().ok()).expect("--ghosting needs a number"),
+            "--accumulate" => persistence.accumulate = true,
+            "--threads" => threads = Some(args.next().and_then(|x| x.parse().ok()).expect("--threads needs a number")),
+            _ => {
+                eprintln!("unknown argument {arg}");
+                std::process::exit(2);
+            }
+        }
+    }
+    let (scene, mut camera) = Scene::load(&level).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+
+    let seed = seed.unwrap_or_else(rand::random);
+    println!("seed {seed}");
+    let rng = RngContext::new(seed);
+    let configure = |renderer: &mut Renderer| {
+        if let Some(threads) = threads {
+            renderer.set_threads(threads);
+        }
+        if let Some(filter) = filter {
+            renderer.set_filter(filter);
+        }
+        renderer.set_persistence(persistence);
+    };
+
+    if headless.is_some() || wav.is_some() {
+        if let Some(path) = headless {
+            let mut renderer = Renderer::headless(width, height);
+            configure(&mut renderer);
+            renderer.draw(&scene, &camera, 1.0 / FPS as f64, &rng.fork(RENDER_RNG).fork(0));
+            renderer.post_process(&post_chain(), scene.dose_at(camera.pos), &rng.fork(POST_RNG).fork(0));
+            renderer.save_bmp(&path).expect("couldn't save frame");
+        }
+        if let Some(path) = wav {
+            // what the player hears standing still at the start
+            let data = audio_for_dose(scene.dose_at(camera.pos));
+            let (mut handler, synth) = AudioHandler::new(Arc::new(Mutex::new(data)), SAMPLE_RATE, &rng.fork(AUDIO_RNG));
+            let mut soundscape = Soundscape::new(&scene, &synth);
+            let samples = handler.render_timeline(&[(0.0, data)], seconds, |dt| soundscape.update(&scene, &camera, &synth, dt));
+            audio::save_wav(&path, &samples, SAMPLE_RATE).expect("couldn't save audio");
+        }
+        return;
+    }
+
     let sdl_context = sdl3::init().expect("couldn't init sdl3");
     let video_subsystem = sdl_context.video().expect("couldn't init video subystem");
 
@@ -39,30 +152,26 @@ fn main() {
     mouse.show_cursor(false);
     mouse.set_relative_mouse_mode(&window, true);
 
-    let audio_data = Arc::new(Mutex::new(AudioData { white_noise: 0.0 }));
+    let audio_data = Arc::new(Mutex::new(AudioData { white_noise: 0.0, dose: 0.0 }));
     let sound = sdl_context.audio().unwrap();
-    let stream = sound.open_playback_stream(&AudioSpec::new(Some(44100), Some(1), Some(AudioFormat::f32_sys())), AudioHandler::new(audio_data.clone(), 44100)).unwrap();
+    let (handler, synth) = AudioHandler::new(audio_data.clone(), SAMPLE_RATE, &rng.fork(AUDIO_RNG));
+    let stream = sound.open_playback_stream(&AudioSpec::new(Some(SAMPLE_RATE as i32), Some(audio::CHANNELS as i32), Some(AudioFormat::f32_sys())), handler).unwrap();
     stream.resume().unwrap();
+    let mut soundscape = Soundscape::new(&scene, &synth);
 
     let mut canvas = window.into_canvas();
     let texture_creator = canvas.texture_creator();
 
-
-    let width = 480;
-    let height = 270;
     let mut renderer =
         Renderer::new(&texture_creator, width, height).expect("couldn't init renderer");
-    let scene = Scene { segments: vec![
-        Segment { a: DVec2::new(1000.0, 0.5), b: DVec2::new(-1000.0, 0.5), texture: Texture::Repeat(bmp::open("./brick.bmp").unwrap()) },
-        Segment { a: DVec2::new(-1000.0, -0.5), b: DVec2::new(1000.0, -0.5), texture: Texture::Repeat(bmp::open("./brick.bmp").unwrap()) },
-        Segment { a: DVec2::new(25.0, -0.5), b: DVec2::new(25.0, 0.5), texture: Texture::Stretch(bmp::open("./brick.bmp").unwrap()) },
-        Segment { a: DVec2::new(0.0, -0.5), b: DVec2::new(0.0, 0.5), texture: Texture::Compound(Box::new(Texture::Glitch(0.5)), Box::new(Texture::Stretch(bmp::open("./eyes.bmp").unwrap())), BlendMode::Multiply) },
-    ] };
-    let mut camera = Camera { pos: DVec2::new(24.5, 0.0), rot: 180.0f64.to_radians(), fov: 66.0f64.to_radians(), noise: 0.0, fog_dist: 1.5 };
+    configure(&mut renderer);
 
     let mut event_pump = sdl_context.event_pump().expect("couldn't init event pump");
 
     let mut dt = 0.0;
+    let mut frame = 0;
+    let mut touching_wall = false;
+    let post = post_chain();
     'mainloop: loop {
         let start = std::time::Instant::now();
         for event in event_pump.poll_iter() {
@@ -116,32 +225,18 @@ fn main() {
             1.5
         };
         movement *= speed * dt;
-        {
-            let hit_data = scene.sample(&Ray { origin: camera.pos, dir: movement });
-            if let Some(data) = hit_data {
-                let b = 0.1;
-                if (0.0..).contains(&data.dist) {
-                    let wall_vec = data.segment.a - data.segment.b;
-                    let mut normal = DVec2::new(wall_vec.y, -wall_vec.x).normalize();
-                    if movement.dot(normal) > 0.0 {
-                        normal = -normal;
-                    }
-                    assert!(movement.dot(normal) <= 0.0);
-                    let theta = FRAC_PI_2 - (normal.dot(-movement.normalize())).acos();
-                    let a = b / theta.tan();
-                    let c = a.hypot(b);
-                    let len = movement.length();
-                    let max_dist = data.dist * len - c;
-                    if len > max_dist {
-                        movement = movement.normalize() * max_dist;
-                    }
-                }
-            }
-            camera.pos += movement;
+        let moved = PLAYER.move_and_slide(&scene, camera.pos, movement);
+        if !moved.collisions.is_empty() && !touching_wall {
+            synth.play(BUMP, 70.0, 0.5, 0.05, Placement::CENTER);
         }
+        touching_wall = !moved.collisions.is_empty();
+        camera.pos = moved.pos;
+        camera.height = scene.floor_at(camera.pos) + Camera::EYE_HEIGHT;
 
-        camera.noise = (1.0 - (camera.pos.x.abs() - 1.0).max(0.0) / 10.0).clamp(0.3, 0.998);
-        audio_data.lock().unwrap().white_noise = (camera.noise - 0.2) as f32 / 3.0;
+        let dose = scene.dose_at(camera.pos);
+        camera.noise = noise_from_dose(dose);
+        soundscape.update(&scene, &camera, &synth, dt);
+        *audio_data.lock().unwrap() = audio_for_dose(dose);
         
         {
             let old_width = renderer.width();
@@ -151,12 +246,17 @@ fn main() {
             let new_height = (height as f64 / (1.0 + 2.0 * (camera.noise - 0.3).max(0.0))) as usize;
             
             if new_height.abs_diff(old_height) >= 10 {
+                let time = renderer.time();
                 let old = renderer.into_cpu_texture();
                 renderer = Renderer::new(&texture_creator, new_width, new_height).unwrap();
+                configure(&mut renderer);
                 renderer.set_cpu_texture(old, old_width, old_height);
+                renderer.set_time(time);
             }
         }
-        renderer.draw(&scene, &camera, dt);
+        renderer.draw(&scene, &camera, dt, &rng.fork(RENDER_RNG).fork(frame));
+        renderer.post_process(&post, dose, &rng.fork(POST_RNG).fork(frame));
+        frame += 1;
 
         // canvas.clear();
         renderer.blit(&mut canvas);
@@ -0,0 +1,96 @@
+//! Coherent noise over 3D space, the third axis usually being time. Everything is a pure function
+//! of the position, so the same point always gets the same value no matter when or where it's sampled.
+
+use glam::DVec3;
+
+/// Smoothly interpolated random values between 0 and 1 on a unit lattice.
+pub fn value(p: DVec3) -> f64 {
+    let cell = p.floor();
+    // smoothstep, so the cells don't show as creases
+    let t = p - cell;
+    let t = t * t * (3.0 - 2.0 * t);
+    trilinear(t, |offset| unit(hash(cell + offset, 0)))
+}
+
+/// Gradient noise, roughly between 0 and 1 and centred on 0.5. Smoother and less blocky than [`value`].
+pub fn perlin(p: DVec3) -> f64 {
+    let cell = p.floor();
+    let local = p - cell;
+    let t = local * local * local * (local * (local * 6.0 - 15.0) + 10.0);
+    let n = trilinear(t, |offset| gradient(hash(cell + offset, 1)).dot(local - offset));
+    (0.5 + 0.5 * n).clamp(0.0, 1.0)
+}
+
+/// Distance to the nearest of one random point per unit cell, clamped to 1. Looks like cells or cracks.
+pub fn worley(p: DVec3) -> f64 {
+    let cell = p.floor();
+    let mut nearest = f64::INFINITY;
+    for z in -1..=1 {
+        for y in -1..=1 {
+            for x in -1..=1 {
+                let neighbour = cell + DVec3::new(x as f64, y as f64, z as f64);
+                let point = neighbour + DVec3::new(unit(hash(neighbour, 2)), unit(hash(neighbour, 3)), unit(hash(neighbour, 4)));
+                nearest = nearest.min(point.distance_squared(p));
+            }
+        }
+    }
+    nearest.sqrt().min(1.0)
+}
+
+/// Sums `octaves` layers of `noise`, each at twice the frequency and half the amplitude of the one before.
+/// Stays between 0 and 1 if `noise` does.
+pub fn fractal(noise: fn(DVec3) -> f64, p: DVec3, octaves: u32) -> f64 {
+    let (mut sum, mut amplitude, mut total, mut frequency) = (0.0, 1.0, 0.0, 1.0);
+    for _ in 0..octaves.max(1) {
+        sum += noise(p * frequency) * amplitude;
+        total += amplitude;
+        amplitude /= 2.0;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+/// Blends the values at the 8 corners of a unit cube.
+fn trilinear(t: DVec3, corner: impl Fn(DVec3) -> f64) -> f64 {
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+    let edge = |y: f64, z: f64| lerp(corner(DVec3::new(0.0, y, z)), corner(DVec3::new(1.0, y, z)), t.x);
+    let face = |z: f64| lerp(edge(0.0, z), edge(1.0, z), t.y);
+    lerp(face(0.0), face(1.0), t.z)
+}
+
+/// one of the 12 directions to the edges of a cube
+fn gradient(hash: u64) -> DVec3 {
+    match hash % 12 {
+        0 => DVec3::new(1.0, 1.0, 0.0),
+        1 => DVec3::new(-1.0, 1.0, 0.0),
+        2 => DVec3::new(1.0, -1.0, 0.0),
+        3 => DVec3::new(-1.0, -1.0, 0.0),
+        4 => DVec3::new(1.0, 0.0, 1.0),
+        5 => DVec3::new(-1.0, 0.0, 1.0),
+        6 => DVec3::new(1.0, 0.0, -1.0),
+        7 => DVec3::new(-1.0, 0.0, -1.0),
+        8 => DVec3::new(0.0, 1.0, 1.0),
+        9 => DVec3::new(0.0, -1.0, 1.0),
+        10 => DVec3::new(0.0, 1.0, -1.0),
+        _ => DVec3::new(0.0, -1.0, -1.0),
+    }
+}
+
+/// A fixed random number for a lattice point, different for each `channel`.
+fn hash(cell: DVec3, channel: u64) -> u64 {
+    let mut hash = (cell.x as i64 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (cell.y as i64 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+        ^ (cell.z as i64 as u64).wrapping_mul(0x1656_67B1_9E37_79F9)
+        ^ channel.wrapping_mul(0xD6E8_FEB8_6659_FD93);
+    // splitmix64 finalizer
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94D0_49BB_1331_11EB);
+    hash ^ (hash >> 31)
+}
+
+/// between 0 and 1
+fn unit(hash: u64) -> f64 {
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,235 @@
+//! Effects applied to a finished frame before it's shown, like a worn out CRT degrading with the dose.
+
+use std::f32::consts::TAU;
+
+use glam::{Vec2, Vec3};
+use rand::Rng;
+
+use crate::random::{GameRng, RngContext};
+
+/// A frame being post-processed.
+pub struct Frame<'a> {
+    pub width: usize,
+    pub height: usize,
+    /// row-major, `width * height` pixels
+    pub pixels: &'a mut [Vec3],
+    /// as big as `pixels` with unspecified contents, for effects that need to read pixels they've already written
+    pub scratch: &'a mut [Vec3],
+    /// another buffer like `scratch`, for effects that need two
+    pub spare: &'a mut [Vec3],
+}
+impl Frame<'_> {
+    /// Copies `pixels` into `scratch`, so effects can read the frame as it was from there.
+    pub fn copy_to_scratch(&mut self) {
+        self.scratch.copy_from_slice(self.pixels);
+    }
+    /// `scratch` at `p` in pixels, blended between the nearest four pixels and clamped to the edges
+    pub fn sample_scratch(&self, p: Vec2) -> Vec3 {
+        let p = p - 0.5;
+        let floor = p.floor();
+        let t = p - floor;
+        let texel = |x: f32, y: f32| {
+            let x = (x as isize).clamp(0, self.width as isize - 1) as usize;
+            let y = (y as isize).clamp(0, self.height as isize - 1) as usize;
+            self.scratch[x + y * self.width]
+        };
+        let top = texel(floor.x, floor.y).lerp(texel(floor.x + 1.0, floor.y), t.x);
+        let bottom = texel(floor.x, floor.y + 1.0).lerp(texel(floor.x + 1.0, floor.y + 1.0), t.x);
+        top.lerp(bottom, t.y)
+    }
+    /// The pixel's position from -1 at the left and top edges to 1 at the right and bottom.
+    fn centered(&self, x: usize, y: usize) -> Vec2 {
+        Vec2::new((x as f32 + 0.5) / self.width as f32, (y as f32 + 0.5) / self.height as f32) * 2.0 - 1.0
+    }
+    /// Back from [`Frame::centered`] to pixels.
+    fn uncentered(&self, p: Vec2) -> Vec2 {
+        (p + 1.0) / 2.0 * Vec2::new(self.width as f32, self.height as f32)
+    }
+}
+
+/// One step of post-processing.
+pub trait Effect: Send + Sync {
+    /// Changes the frame in place. `strength` is between 0 and 1, at 0 the frame should be left alone.
+    fn apply(&self, frame: &mut Frame, strength: f32, rng: &mut GameRng);
+}
+
+/// How strong an effect is at a dose, `base` with no dose and approaching 1 as it rises.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Response {
+    pub base: f32,
+    /// how quickly the strength approaches 1, per unit of dose
+    pub rate: f64,
+}
+impl Response {
+    /// full strength no matter the dose
+    pub const ALWAYS: Response = Response { base: 1.0, rate: 0.0 };
+
+    pub fn strength(&self, dose: f64) -> f32 {
+        let t = 1.0 - (-self.rate * dose.max(0.0)).exp();
+        self.base + (1.0 - self.base) * t as f32
+    }
+}
+
+/// Effects applied one after the other.
+#[derive(Default)]
+pub struct PostChain {
+    stages: Vec<(Box<dyn Effect>, Response)>,
+}
+impl PostChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds an effect after the ones already in the chain.
+    pub fn push(&mut self, effect: impl Effect + 'static, response: Response) {
+        self.stages.push((Box::new(effect), response));
+    }
+    /// Applies every effect with its strength at `dose`, effects at a strength of 0 are skipped.
+    pub fn apply(&self, frame: &mut Frame, dose: f64, rng: &RngContext) {
+        for (i, (effect, response)) in self.stages.iter().enumerate() {
+            let strength = response.strength(dose);
+            if strength > 0.0 {
+                effect.apply(frame, strength, &mut rng.stream(i as u64));
+            }
+        }
+    }
+}
+
+/// Pulls red outwards and blue inwards, more towards the edges, like a cheap lens.
+#[derive(Debug, Clone, Copy)]
+pub struct ChromaticAberration {
+    /// how far red and blue are pulled apart at the edges, as a fraction of the distance from the middle
+    pub offset: f32,
+}
+impl Effect for ChromaticAberration {
+    fn apply(&self, frame: &mut Frame, strength: f32, _: &mut GameRng) {
+        frame.copy_to_scratch();
+        let offset = self.offset * strength;
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let p = frame.centered(x, y);
+                let red = frame.sample_scratch(frame.uncentered(p * (1.0 - offset))).x;
+                let blue = frame.sample_scratch(frame.uncentered(p * (1.0 + offset))).z;
+                let pixel = &mut frame.pixels[x + y * frame.width];
+                pixel.x = red;
+                pixel.z = blue;
+            }
+        }
+    }
+}
+
+/// Darkens every other row, or every `period`th.
+#[derive(Debug, Clone, Copy)]
+pub struct Scanlines {
+    /// how much the darkest rows are darkened
+    pub darkness: f32,
+    /// rows from one dark row to the next
+    pub period: f32,
+}
+impl Effect for Scanlines {
+    fn apply(&self, frame: &mut Frame, strength: f32, _: &mut GameRng) {
+        for (y, row) in frame.pixels.chunks_mut(frame.width).enumerate() {
+            let dark = 0.5 - 0.5 * (TAU * y as f32 / self.period).cos();
+            let factor = 1.0 - self.darkness * strength * dark;
+            row.iter_mut().for_each(|pixel| *pixel *= factor);
+        }
+    }
+}
+
+/// Bulges the picture out like a curved screen, leaving the corners black.
+#[derive(Debug, Clone, Copy)]
+pub struct Curvature {
+    /// how much further out the corners are pulled in from, 0 is flat
+    pub amount: f32,
+}
+impl Effect for Curvature {
+    fn apply(&self, frame: &mut Frame, strength: f32, _: &mut GameRng) {
+        frame.copy_to_scratch();
+        let amount = self.amount * strength;
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let p = frame.centered(x, y);
+                let source = p * (1.0 + amount * p.length_squared() / 2.0);
+                frame.pixels[x + y * frame.width] = if source.abs().max_element() <= 1.0 {
+                    frame.sample_scratch(frame.uncentered(source))
+                } else {
+                    Vec3::ZERO
+                };
+            }
+        }
+    }
+}
+
+/// Darkens towards the corners.
+#[derive(Debug, Clone, Copy)]
+pub struct Vignette {
+    /// how much the corners are darkened
+    pub amount: f32,
+}
+impl Effect for Vignette {
+    fn apply(&self, frame: &mut Frame, strength: f32, _: &mut GameRng) {
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                // 1 in the corners
+                let edge = frame.centered(x, y).length_squared() / 2.0;
+                frame.pixels[x + y * frame.width] *= 1.0 - self.amount * strength * edge * edge;
+            }
+        }
+    }
+}
+
+/// Bright parts bleed light into their surroundings.
+#[derive(Debug, Clone, Copy)]
+pub struct Bloom {
+    /// only brightness above this bleeds
+    pub threshold: f32,
+    /// how far it bleeds, in pixels
+    pub radius: usize,
+    pub intensity: f32,
+}
+impl Effect for Bloom {
+    fn apply(&self, frame: &mut Frame, strength: f32, _: &mut GameRng) {
+        let (width, height) = (frame.width, frame.height);
+        for (bright, pixel) in frame.scratch.iter_mut().zip(frame.pixels.iter()) {
+            *bright = (*pixel - self.threshold).max(Vec3::ZERO);
+        }
+        // a box blur along rows and then columns, which looks close enough to a gaussian once it's this faint
+        box_blur(frame.scratch, frame.spare, width, height, true, self.radius);
+        box_blur(frame.spare, frame.scratch, width, height, false, self.radius);
+        let gain = self.intensity * strength;
+        for (pixel, bloom) in frame.pixels.iter_mut().zip(frame.scratch.iter()) {
+            *pixel += *bloom * gain;
+        }
+    }
+}
+
+/// Averages every pixel with the ones up to `radius` pixels away along its row, or its column if not `horizontal`.
+fn box_blur(from: &[Vec3], to: &mut [Vec3], width: usize, height: usize, horizontal: bool, radius: usize) {
+    // lines are rows or columns, `step` apart from one pixel to the next and `stride` from one line to the next
+    let (lines, length, step, stride) = if horizontal { (height, width, 1, width) } else { (width, height, width, 1) };
+    let at = |i: isize| i.clamp(0, length as isize - 1) as usize * step;
+    let count = (2 * radius + 1) as f32;
+    for line in 0..lines {
+        let start = line * stride;
+        // a running sum over the window, edges are repeated
+        let mut sum = (-(radius as isize)..=radius as isize).map(|i| from[start + at(i)]).sum::<Vec3>();
+        for i in 0..length as isize {
+            to[start + i as usize * step] = sum / count;
+            sum += from[start + at(i + radius as isize + 1)] - from[start + at(i - radius as isize)];
+        }
+    }
+}
+
+/// Flickering noise over the whole picture.
+#[derive(Debug, Clone, Copy)]
+pub struct Grain {
+    /// the most a pixel is brightened or darkened by
+    pub amount: f32,
+}
+impl Effect for Grain {
+    fn apply(&self, frame: &mut Frame, strength: f32, rng: &mut GameRng) {
+        let amount = self.amount * strength;
+        for pixel in frame.pixels.iter_mut() {
+            *pixel += Vec3::splat(rng.random_range(-amount..=amount));
+        }
+    }
+}
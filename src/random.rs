@@ -0,0 +1,37 @@
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+/// The rng used by everything that has to be reproducible from a seed.
+/// A named algorithm rather than `SmallRng`, whose algorithm can differ between platforms and rand versions.
+pub type GameRng = Xoshiro256PlusPlus;
+
+/// A seed that independent rng streams are derived from, so the same seed and inputs
+/// always give the same frames and audio no matter how the work is scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RngContext {
+    seed: u64,
+}
+impl RngContext {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    /// A child context, e.g. one per subsystem or per frame.
+    pub fn fork(&self, key: u64) -> Self {
+        Self { seed: mix(self.seed, key) }
+    }
+    /// An rng stream, e.g. one per column or per audio stream.
+    pub fn stream(&self, key: u64) -> GameRng {
+        GameRng::seed_from_u64(mix(self.seed, key))
+    }
+}
+
+/// splitmix64 style hash
+fn mix(a: u64, b: u64) -> u64 {
+    let mut z = a ^ b.wrapping_add(0x9e3779b97f4a7c15).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
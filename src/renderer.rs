@@ -1,17 +1,42 @@
-use glam::{DVec2, Vec3};
-use rand::{distr::{Bernoulli, Distribution}, rng};
+use std::{ops::Range, path::Path};
+
+use glam::{DVec2, DVec3, Vec3, Vec4};
+use rand::distr::{Bernoulli, Distribution};
 use sdl3::{
     pixels::PixelFormat,
     render::{Canvas, RenderTarget, Texture, TextureAccess, TextureCreator, TextureValueError}, sys::pixels::SDL_PIXELFORMAT_RGB96_FLOAT,
 };
 
-use crate::{camera::{Camera, Ray}, scene::{HitData, Scene}};
+use crate::{camera::{Camera, Ray}, random::{GameRng, RngContext}, scene::{Flats, HitData, LightProbe, LightSweep, Scene, Sprite}, texture::{Filter, SampleContext}, post::{Frame, PostChain}};
 
 pub struct Renderer<'a> {
-    texture: Texture<'a>,
+    /// `None` when rendering headless
+    texture: Option<Texture<'a>>,
     cpu_texture: Vec<Vec3>,
+    /// column-major scratch buffer the columns are drawn into
+    columns: Vec<Vec3>,
+    /// column-major number of frames averaged into each pixel while accumulating
+    samples: Vec<u32>,
+    /// `cpu_texture` after post-processing, what's shown. Kept apart so effects don't pile up from frame to frame
+    output: Vec<Vec3>,
+    /// spare buffers for post-processing effects
+    scratch: Vec<Vec3>,
+    spare: Vec<Vec3>,
     width: usize,
     height: usize,
+    threads: usize,
+    filter: Filter,
+    /// seconds drawn so far, the clock animated textures run on
+    time: f64,
+    persistence: Persistence,
+    /// where the camera was last frame, as position, rotation, height and fov
+    last_view: Option<(DVec2, f64, f64, f64)>,
+}
+impl Renderer<'static> {
+    /// Creates a renderer that only draws into its cpu frame buffer, without needing sdl.
+    pub fn headless(width: usize, height: usize) -> Self {
+        Self::with_texture(None, width, height)
+    }
 }
 impl<'a> Renderer<'a> {
     pub fn new<T: 'a>(
@@ -19,89 +44,135 @@ impl<'a> Renderer<'a> {
         width: usize,
         height: usize,
     ) -> Result<Self, TextureValueError> {
-        let mut x = Self {
-            texture: texture_creator.create_texture(
-                unsafe { PixelFormat::from_ll(SDL_PIXELFORMAT_RGB96_FLOAT) },
-                TextureAccess::Streaming,
-                width as u32,
-                height as u32,
-            )?,
+        let mut texture = texture_creator.create_texture(
+            unsafe { PixelFormat::from_ll(SDL_PIXELFORMAT_RGB96_FLOAT) },
+            TextureAccess::Streaming,
+            width as u32,
+            height as u32,
+        )?;
+        texture.set_scale_mode(sdl3::render::ScaleMode::Nearest);
+        Ok(Self::with_texture(Some(texture), width, height))
+    }
+    fn with_texture(texture: Option<Texture<'a>>, width: usize, height: usize) -> Self {
+        Self {
+            texture,
             width,
             height,
             cpu_texture: vec![Vec3::ZERO; width * height],
+            columns: vec![Vec3::ZERO; width * height],
+            samples: vec![0; width * height],
+            output: vec![Vec3::ZERO; width * height],
+            scratch: vec![Vec3::ZERO; width * height],
+            spare: vec![Vec3::ZERO; width * height],
+            threads: default_threads(),
+            filter: Filter::default(),
+            time: 0.0,
+            persistence: Persistence::default(),
+            last_view: None,
+        }
+    }
+    /// `rng` should be forked per frame, the same context and inputs always draw the same frame
+    pub fn draw(&mut self, scene: &Scene, camera: &Camera, dt: f64, rng: &RngContext) {
+        self.time += dt;
+        let persistence = self.persistence;
+        let view = (camera.pos, camera.rot, camera.height, camera.fov);
+        let still = persistence.accumulate && self.last_view == Some(view);
+        self.last_view = Some(view);
+        if !still {
+            self.samples.fill(0);
+        }
+        // accumulating keeps every frame at full weight instead of letting the old ones fade
+        let decay = if still {
+            Vec3::ONE
+        } else {
+            (persistence.decay.as_dvec3() * ((1.0 - camera.noise/2.0) * -dt)).map(f64::exp).as_vec3()
         };
-        x.texture.set_scale_mode(sdl3::render::ScaleMode::Nearest);
-        Ok(x)
-    }
-    pub fn draw(&mut self, scene: &Scene, camera: &Camera, dt: f64) {
-        let mut rng = rng();
-        for pixel in self.cpu_texture.iter_mut() {
-            *pixel *= ((1.0 - camera.noise/2.0) * -dt).exp() as f32;
-        }
-        let distribution = Bernoulli::new(camera.noise.min(1.0)).unwrap();
-        for (x, ray) in camera.get_rays(self.width).enumerate() {
-            match scene.sample(&ray) {
-                Some(HitData {
-                    mut dist,
-                    point,
-                    u,
-                    segment,
-                }) => {
-                    // correct fish eye
-                    dist = camera.get_perp_dist_to(point);
-                    
-                    let projection_distance = self.width as f64 / (2.0 * (camera.fov/2.0).tan());
-                    let height = (projection_distance / dist) as usize & (!1);
-                    let clamped_height = height.min(self.height); // clamp height to screen
-                    let space = (self.height - clamped_height) / 2;
-
-                    let end_y = space + clamped_height;
-
-                    for y in 0..self.height {
-                        if (space..end_y).contains(&y) {
-                            if !segment.texture.contains_glitch() && distribution.sample(&mut rng) {
-                                continue;
-                            }
-                            let mut color = segment.texture.sample(DVec2::new(u, (y + height/2 - self.height/2) as f64 / height as f64), (segment.b - segment.a).length(), &mut rng);
-                            color *= (camera.fog_dist / dist).min(1.0) as f32;
-                            self.set_pixel(x, y, color);
-                        } else {
-                            if distribution.sample(&mut rng) {
-                                continue;
-                            }
-                            let color = floor_ceil(y, self.width, self.height, &ray, camera);
-                            self.set_pixel(x, y, color);
-                        }
-                    }
-                },
-                None => {
-                    for y in 0..self.height {
-                        if distribution.sample(&mut rng) {
-                            continue;
-                        }
-                        let color = floor_ceil(y, self.width, self.height, &ray, camera);
-                        self.set_pixel(x, y, color);
-                    }
-                },
+        let blend = if still {
+            Blend::Accumulate
+        } else if persistence.ghosting > 0.0 {
+            Blend::Ghost(persistence.ghosting)
+        } else {
+            Blend::Replace
+        };
+        let distribution = Bernoulli::new((camera.noise * persistence.dropout).clamp(0.0, 1.0)).unwrap();
+        let rays: Vec<Ray> = camera.get_rays(self.width).collect();
+        let sprites = project_sprites(scene, camera, self.width, self.height);
+        let frame = FrameContext { scene, camera, sprites: &sprites, distribution: &distribution, width: self.width, filter: self.filter, time: self.time, blend };
+
+        // columns are rendered column-major so each thread gets a contiguous block
+        let (width, height) = (self.width, self.height);
+        let cpu_texture = &self.cpu_texture;
+        let draw_block = |first_x: usize, block: &mut [Vec3], samples: &mut [u32]| {
+            let mut scratch = ColumnScratch::new(height);
+            let columns = block.chunks_mut(height).zip(samples.chunks_mut(height));
+            for (i, (column, samples)) in columns.enumerate() {
+                let x = first_x + i;
+                for (y, pixel) in column.iter_mut().enumerate() {
+                    *pixel = cpu_texture[x + y*width] * decay;
+                }
+                let mut rng = rng.stream(x as u64);
+                draw_column(&frame, x, &rays[x], column, samples, &mut scratch, &mut rng);
+            }
+        };
+        let columns_per_thread = width.div_ceil(self.threads.max(1));
+        if columns_per_thread >= width {
+            draw_block(0, &mut self.columns, &mut self.samples);
+        } else {
+            std::thread::scope(|s| {
+                let block_len = columns_per_thread * height;
+                let blocks = self.columns.chunks_mut(block_len).zip(self.samples.chunks_mut(block_len));
+                for (i, (block, samples)) in blocks.enumerate() {
+                    s.spawn(move || draw_block(i * columns_per_thread, block, samples));
+                }
+            });
+        }
+        for (x, column) in self.columns.chunks(height).enumerate() {
+            for (y, color) in column.iter().enumerate() {
+                self.cpu_texture[x + y*width] = *color;
             }
         }
 
-        self.texture.with_lock(None, |x, y| {
-            if y != self.width * size_of::<Vec3>() {
-                for (bytes, colors) in x.chunks_mut(y).zip(self.cpu_texture.chunks(self.width)) {
-                    bytes[..std::mem::size_of_val(colors)].copy_from_slice(unsafe {
-                        std::slice::from_raw_parts(colors.as_ptr().cast(), std::mem::size_of_val(colors))
+        self.output.copy_from_slice(&self.cpu_texture);
+    }
+    /// Runs the chain over the last drawn frame, with effects as strong as `dose` makes them.
+    /// Replaces any earlier post-processing of the same frame.
+    pub fn post_process(&mut self, chain: &PostChain, dose: f64, rng: &RngContext) {
+        self.output.copy_from_slice(&self.cpu_texture);
+        let mut frame = Frame { width: self.width, height: self.height, pixels: &mut self.output, scratch: &mut self.scratch, spare: &mut self.spare };
+        chain.apply(&mut frame, dose, rng);
+    }
+    /// does nothing for a headless renderer
+    pub fn blit(&mut self, canvas: &mut Canvas<impl RenderTarget>) {
+        if let Some(texture) = &mut self.texture {
+            let width = self.width;
+            let output = &self.output;
+            texture.with_lock(None, |x, y| {
+                if y != width * size_of::<Vec3>() {
+                    for (bytes, colors) in x.chunks_mut(y).zip(output.chunks(width)) {
+                        bytes[..std::mem::size_of_val(colors)].copy_from_slice(unsafe {
+                            std::slice::from_raw_parts(colors.as_ptr().cast(), std::mem::size_of_val(colors))
+                        });
+                    }
+                } else {
+                    x.copy_from_slice(unsafe {
+                        std::slice::from_raw_parts(output.as_ptr().cast(), std::mem::size_of_val(output.as_slice()))
                     });
                 }
-            } else {
-                x.copy_from_slice(unsafe {
-                    std::slice::from_raw_parts(self.cpu_texture.as_ptr().cast(), std::mem::size_of_val(self.cpu_texture.as_slice()))
-                });
-            }
-        }).expect("texture error");
+            }).expect("texture error");
+            canvas.copy(texture, None, None).unwrap();
+        }
+    }
+    /// the last drawn frame after post-processing, row-major, `width * height` pixels
+    pub fn frame(&self) -> &[Vec3] {
+        &self.output
     }
-    pub fn blit(&self, canvas: &mut Canvas<impl RenderTarget>) {
-        canvas.copy(&self.texture, None, None).unwrap();
+    /// writes the last drawn frame to a bmp file, clamping colors to `0.0..=1.0`
+    pub fn save_bmp(&self, path: impl AsRef<Path>) -> image::ImageResult<()> {
+        let image = image::RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let color = self.frame()[x as usize + y as usize * self.width];
+            image::Rgb((color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).round().to_array().map(|x| x as u8))
+        });
+        image.save_with_format(path, image::ImageFormat::Bmp)
     }
     pub fn set_cpu_texture(&mut self, texture: Vec<Vec3>, old_width: usize, old_height: usize) {
         for (x, color) in self.cpu_texture.iter_mut().enumerate() {
@@ -116,6 +187,14 @@ impl<'a> Renderer<'a> {
             *color = texture[a + b*old_width];
         }
     }
+    /// Seconds drawn so far, see [`Renderer::set_time`].
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+    /// Sets the clock animated textures run on, e.g. to carry it over to a new renderer so they don't jump back.
+    pub fn set_time(&mut self, time: f64) {
+        self.time = time;
+    }
     pub fn into_cpu_texture(self) -> Vec<Vec3> {
         self.cpu_texture
     }
@@ -125,29 +204,386 @@ impl<'a> Renderer<'a> {
     pub fn height(&self) -> usize {
         self.height
     }
+    /// Number of threads columns are split across, the output doesn't depend on it.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+    pub fn set_persistence(&mut self, persistence: Persistence) {
+        self.persistence = persistence;
+    }
+}
 
-    /// no bounds check for x
-    fn set_pixel(&mut self, x: usize, y: usize, col: Vec3) {
-        self.cpu_texture[x + y*self.width] = Vec3::new(col.x.powi(2), col.y.powi(2), col.z.powi(2));
+/// How each frame lingers under the next, like the phosphor of an old screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Persistence {
+    /// how fast old pixels fade per second for each color channel, slowed down by the camera's noise
+    pub decay: Vec3,
+    /// scales the chance of a pixel keeping its old color instead of being drawn, which is the camera's noise
+    pub dropout: f64,
+    /// how much of the old pixel stays mixed into a freshly drawn one, leaving trails behind moving things
+    pub ghosting: f32,
+    /// while the camera holds still, average the frames instead, settling into a clean picture
+    pub accumulate: bool,
+}
+impl Default for Persistence {
+    fn default() -> Self {
+        Self { decay: Vec3::ONE, dropout: 1.0, ghosting: 0.0, accumulate: false }
     }
 }
 
-fn floor_ceil(y: usize, width: usize, height: usize, r: &Ray, camera: &Camera) -> Vec3 {
-    let v = if y < height/2 {
-        1.0 - y as f64 / height as f64 * 2.0
-    } else {
-        (y + 1) as f64 / height as f64 * 2.0 - 1.0
-    };
+/// How freshly drawn pixels replace the old ones this frame.
+#[derive(Debug, Clone, Copy)]
+enum Blend {
+    Replace,
+    /// keeps this much of the old pixel
+    Ghost(f32),
+    /// a running average of every frame since the camera stopped
+    Accumulate,
+}
+
+/// Everything shared by all columns of a frame.
+struct FrameContext<'a> {
+    scene: &'a Scene,
+    camera: &'a Camera,
+    /// back to front
+    sprites: &'a [ProjectedSprite<'a>],
+    distribution: &'a Bernoulli,
+    width: usize,
+    filter: Filter,
+    time: f64,
+    blend: Blend,
+}
 
-    let corrected_dist = 1.0 / v;
-    let scale = ((width as f64 / height as f64) / (camera.fov/2.0).tan()) / 2.0;
-    let real_dist = corrected_dist / r.dir.project_onto(DVec2::from_angle(camera.rot)).length() * scale;
+/// Per pixel compositing state, reused between columns.
+struct ColumnScratch {
+    /// color composited so far, front to back
+    color: Vec<Vec3>,
+    /// how much of whatever is further away still shows through, 0 once something opaque is drawn
+    transmittance: Vec<f32>,
+    /// whether the pixel keeps last frame's color, decided by the first surface reaching it
+    dropped: Vec<Option<bool>>,
+    /// the lights reaching the wall being drawn
+    probe: LightProbe,
+    /// the walls that might shadow the floor and ceiling being drawn
+    sweep: LightSweep,
+}
+impl ColumnScratch {
+    fn new(height: usize) -> Self {
+        Self { color: vec![Vec3::ZERO; height], transmittance: vec![1.0; height], dropped: vec![None; height], probe: LightProbe::default(), sweep: LightSweep::default() }
+    }
+}
 
-    let pos  = r.origin + r.dir * real_dist;
-    let color = if (pos.x.floor() + pos.y.floor()) % 2.0 == 0.0 {
-        Vec3::ZERO
-    } else {
-        Vec3::splat(0.2)
+/// State for drawing one column front to back. Translucent surfaces are composited
+/// front to back ("under"), which gives the same result as blending them back to front.
+struct ColumnPass<'a> {
+    scene: &'a Scene,
+    camera: &'a Camera,
+    ray: &'a Ray,
+    pixels: &'a mut [Vec3],
+    /// frames averaged into each pixel, see [`Blend::Accumulate`]
+    samples: &'a mut [u32],
+    scratch: &'a mut ColumnScratch,
+    /// pixels not yet covered by something opaque
+    remaining: usize,
+    distribution: &'a Bernoulli,
+    rng: &'a mut GameRng,
+    horizon: f64,
+    projection_distance: f64,
+    /// perpendicular distance per unit of ray distance, to correct fish eye
+    perp_scale: f64,
+    filter: Filter,
+    time: f64,
+    blend: Blend,
+}
+impl ColumnPass<'_> {
+    /// screen y of height `z` at perpendicular distance `dist`
+    fn screen_y(&self, z: f64, dist: f64) -> f64 {
+        self.horizon - (z - self.camera.height) * self.projection_distance / dist
+    }
+    /// rows whose centres are between `top` and `bottom`
+    fn rows(&self, top: f64, bottom: f64) -> Range<usize> {
+        let height = self.pixels.len() as f64;
+        let first = (top - 0.5).ceil().clamp(0.0, height) as usize;
+        let last = (bottom - 0.5).ceil().clamp(0.0, height) as usize;
+        first..last.max(first)
+    }
+    /// Composites a surface behind everything already drawn in the pixel.
+    /// `opaque` surfaces are known to cover the pixel without sampling them,
+    /// `color` isn't sampled if the pixel drops out.
+    fn fill(&mut self, y: usize, glitch: bool, opaque: bool, color: impl FnOnce(&mut GameRng) -> Vec4) {
+        let scratch = &mut *self.scratch;
+        if scratch.transmittance[y] == 0.0 {
+            return;
+        }
+        let dropped = *scratch.dropped[y].get_or_insert_with(|| !glitch && self.distribution.sample(self.rng));
+        let alpha = if dropped {
+            if !opaque {
+                return;
+            }
+            1.0
+        } else {
+            let color = color(self.rng);
+            scratch.color[y] += color.truncate() * color.w * scratch.transmittance[y];
+            color.w
+        };
+        scratch.transmittance[y] *= 1.0 - alpha.clamp(0.0, 1.0);
+        // close enough to opaque that nothing behind would show
+        if scratch.transmittance[y] < 1.0 / 256.0 {
+            scratch.transmittance[y] = 0.0;
+            self.remaining -= 1;
+        }
+    }
+    fn draw_wall(&mut self, hit: &HitData) {
+        let segment = hit.segment;
+        let dist = hit.dist * self.perp_scale;
+        let rows = self.rows(self.screen_y(segment.top, dist), self.screen_y(segment.bottom, dist));
+        let context = SampleContext {
+            size: DVec2::new((segment.b - segment.a).length(), segment.top - segment.bottom),
+            footprint: dist / self.projection_distance,
+            filter: self.filter,
+            time: self.time,
+        };
+        let fog = &self.camera.fog;
+        let glitch = segment.texture.contains_glitch();
+        let opaque = segment.opacity >= 1.0 && segment.texture.is_opaque();
+        // lit on the side facing the camera
+        let mut normal = (segment.b - segment.a).perp().normalize_or_zero();
+        if normal.dot(self.camera.pos - hit.point) < 0.0 {
+            normal = -normal;
+        }
+        let scene = self.scene;
+        // the shadow rays are the same all the way up the column, only which walls they pass over changes
+        let mut probe = std::mem::take(&mut self.scratch.probe);
+        scene.probe_lights(hit.point, &mut probe);
+        for y in rows {
+            let z = self.camera.height - (y as f64 + 0.5 - self.horizon) * dist / self.projection_distance;
+            let v = (segment.top - z) / (segment.top - segment.bottom);
+            self.fill(y, glitch, opaque, |rng| {
+                let color = segment.texture.sample_rgba(DVec2::new(hit.u, v), &context, rng);
+                let light = scene.probed_light_at(&probe, z, Some(normal.extend(0.0)));
+                fog.apply(color.truncate() * light, dist).extend(color.w * segment.opacity)
+            });
+        }
+        self.scratch.probe = probe;
+    }
+    fn draw_sprite(&mut self, projected: &ProjectedSprite, x: f64) {
+        let sprite = projected.sprite;
+        let u = (x - projected.left) / (projected.right - projected.left);
+        let fog = &self.camera.fog;
+        let glitch = sprite.texture.contains_glitch();
+        let opaque = sprite.texture.is_opaque();
+        let context = SampleContext { size: DVec2::splat(sprite.size), footprint: projected.dist / self.projection_distance, filter: self.filter, time: self.time };
+        // lit as a whole from its middle, sprites are too small for shadows across them to matter
+        let light = self.scene.light_at(sprite.pos.extend(self.scene.floor_at(sprite.pos) + sprite.size / 2.0), None);
+        for y in self.rows(projected.top, projected.bottom) {
+            let v = (y as f64 + 0.5 - projected.top) / (projected.bottom - projected.top);
+            self.fill(y, glitch, opaque, |rng| {
+                let color = sprite.texture.sample_rgba(DVec2::new(u, v), &context, rng);
+                fog.apply(color.truncate() * light, projected.dist).extend(color.w)
+            });
+        }
+    }
+    /// Draws the floor and ceiling between perpendicular distances `near` and `far`.
+    fn draw_flats(&mut self, near: f64, far: f64, flats: &Flats) {
+        let camera = self.camera;
+        // the shadow rays to this stretch of floor and ceiling all pass the same few walls
+        let mut sweep = std::mem::take(&mut self.scratch.sweep);
+        let along = |dist: f64| self.ray.origin + self.ray.dir * (dist / self.perp_scale);
+        self.scene.sweep_lights(along(near), along(far), &mut sweep);
+        if flats.floor < camera.height {
+            let k = (camera.height - flats.floor) * self.projection_distance;
+            for y in self.rows(self.horizon + k / far, self.horizon + k / near) {
+                let dist = k / (y as f64 + 0.5 - self.horizon);
+                self.draw_flat(y, dist, k, flats.floor, flats.floor_texture, &sweep);
+            }
+        }
+        if flats.ceiling > camera.height {
+            let k = (flats.ceiling - camera.height) * self.projection_distance;
+            for y in self.rows(self.horizon - k / near, self.horizon - k / far) {
+                let dist = k / (self.horizon - y as f64 - 0.5);
+                self.draw_flat(y, dist, k, flats.ceiling, flats.ceiling_texture, &sweep);
+            }
+        }
+        self.scratch.sweep = sweep;
+    }
+    /// floors and ceilings are always opaque, there's nothing behind them
+    /// `k` is the height below or above the eye times the projection distance, `dist` is `k` over the rows from the horizon,
+    /// `z` is the height of the flat
+    fn draw_flat(&mut self, y: usize, dist: f64, k: f64, z: f64, texture: &crate::texture::Texture, sweep: &LightSweep) {
+        let ray_dist = dist / self.perp_scale;
+        let pos = self.ray.origin + self.ray.dir * ray_dist;
+        let fog = &self.camera.fog;
+        // a row covers far more floor in depth than across once it's close to the horizon
+        let footprint = (dist / self.projection_distance).max(dist * dist / k) / self.perp_scale;
+        let context = SampleContext { size: DVec2::ONE, footprint, filter: self.filter, time: self.time };
+        // floors face up and ceilings down, towards the eye either way
+        let normal = DVec3::Z * (self.camera.height - z).signum();
+        let scene = self.scene;
+        self.fill(y, texture.contains_glitch(), true, |rng| {
+            fog.apply(texture.sample(pos, &context, rng) * scene.swept_light_at(sweep, pos.extend(z), Some(normal)), dist).extend(1.0)
+        });
+    }
+    /// Fills whatever nothing was drawn in with the fog's color.
+    fn draw_sky(&mut self) {
+        let color = self.camera.fog.color;
+        for y in 0..self.pixels.len() {
+            self.fill(y, false, true, |_| color.extend(1.0));
+        }
+    }
+    /// Writes the composited colors, pixels that dropped out keep their old color.
+    fn finish(self) {
+        for (y, pixel) in self.pixels.iter_mut().enumerate() {
+            if self.scratch.dropped[y] == Some(false) {
+                let fresh = gamma(self.scratch.color[y]) + *pixel * self.scratch.transmittance[y];
+                *pixel = match self.blend {
+                    Blend::Replace => fresh,
+                    Blend::Ghost(ghosting) => fresh.lerp(*pixel, ghosting),
+                    Blend::Accumulate => {
+                        self.samples[y] += 1;
+                        pixel.lerp(fresh, 1.0 / self.samples[y] as f32)
+                    }
+                };
+            }
+        }
+    }
+}
+
+/// Draws one column nearest first: each wall and sprite along the ray, with the floor and ceiling
+/// between them. Shorter walls don't hide what's behind them, and the floor and ceiling
+/// come from the area on the far side of each wall.
+fn draw_column(frame: &FrameContext, x: usize, ray: &Ray, pixels: &mut [Vec3], samples: &mut [u32], scratch: &mut ColumnScratch, rng: &mut GameRng) {
+    let (scene, camera) = (frame.scene, frame.camera);
+    scratch.color.fill(Vec3::ZERO);
+    scratch.transmittance.fill(1.0);
+    scratch.dropped.fill(None);
+    let mut pass = ColumnPass {
+        scene,
+        camera,
+        ray,
+        remaining: pixels.len(),
+        horizon: pixels.len() as f64 / 2.0,
+        pixels,
+        samples,
+        scratch,
+        distribution: frame.distribution,
+        rng,
+        projection_distance: frame.width as f64 / (2.0 * (camera.fov/2.0).tan()),
+        perp_scale: ray.dir.dot(DVec2::from_angle(camera.rot)),
+        filter: frame.filter,
+        time: frame.time,
+        blend: frame.blend,
     };
-    color * (camera.fog_dist / real_dist).min(1.0) as f32
+
+    let x = x as f64;
+    let mut sprites = frame.sprites.iter().rev().filter(|sprite| (sprite.left..sprite.right).contains(&x)).peekable();
+    let mut near = 0.0;
+    let mut flats = scene.flats_at(camera.pos);
+    let far = camera.fog.max_dist;
+    for hit in scene.hits(ray) {
+        let dist = hit.dist * pass.perp_scale;
+        if dist > far {
+            break;
+        }
+        while let Some(sprite) = sprites.next_if(|sprite| sprite.dist < dist) {
+            pass.draw_flats(near, sprite.dist, &flats);
+            pass.draw_sprite(sprite, x);
+            near = sprite.dist;
+        }
+        pass.draw_flats(near, dist, &flats);
+        pass.draw_wall(&hit);
+        if pass.remaining == 0 {
+            break;
+        }
+        near = dist;
+        flats = scene.flats_at(hit.point + ray.dir * 1e-6);
+    }
+    if pass.remaining > 0 {
+        for sprite in sprites.take_while(|sprite| sprite.dist < far) {
+            pass.draw_flats(near, sprite.dist, &flats);
+            pass.draw_sprite(sprite, x);
+            near = sprite.dist;
+        }
+        pass.draw_flats(near, far, &flats);
+        pass.draw_sky();
+    }
+    pass.finish();
+}
+
+/// A sprite's screen rectangle, in columns and rows.
+struct ProjectedSprite<'a> {
+    sprite: &'a Sprite,
+    /// perpendicular distance, comparable to wall distances
+    dist: f64,
+    left: f64,
+    right: f64,
+    top: f64,
+    bottom: f64,
+}
+
+/// Projects the sprites in front of the camera, sorted back to front.
+fn project_sprites<'a>(scene: &'a Scene, camera: &Camera, width: usize, height: usize) -> Vec<ProjectedSprite<'a>> {
+    let forward = DVec2::from_angle(camera.rot);
+    let projection_distance = width as f64 / (2.0 * (camera.fov/2.0).tan());
+    // rays are spread evenly by angle, see `Camera::get_rays`
+    let column = |angle: f64| (angle / camera.fov + 0.5) * (width - 1) as f64;
+    let screen_y = |z: f64, dist: f64| height as f64 / 2.0 - (z - camera.height) * projection_distance / dist;
+
+    let mut sprites: Vec<_> = scene.sprites.iter().filter_map(|sprite| {
+        let to_sprite = sprite.pos - camera.pos;
+        let dist = to_sprite.dot(forward);
+        if dist <= 0.0 {
+            return None;
+        }
+        let angle = forward.angle_to(to_sprite);
+        let half_width = (sprite.size / 2.0 / to_sprite.length()).atan();
+        let floor = scene.floor_at(sprite.pos);
+        Some(ProjectedSprite {
+            sprite,
+            dist,
+            left: column(angle - half_width),
+            right: column(angle + half_width),
+            top: screen_y(floor + sprite.size, dist),
+            bottom: screen_y(floor, dist),
+        })
+    }).collect();
+    sprites.sort_by(|a, b| b.dist.total_cmp(&a.dist));
+    sprites
+}
+
+fn gamma(col: Vec3) -> Vec3 {
+    Vec3::new(col.x.powi(2), col.y.powi(2), col.z.powi(2))
+}
+
+fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{camera::Fog, scene::{tests::segment, Segment}, texture::Texture};
+
+    #[test]
+    fn threads_draw_the_same_frame() {
+        // a room with a glitching wall and a sprite, and enough noise for pixels to drop out, so every column uses its rng
+        let corners = [DVec2::new(-4.0, -3.0), DVec2::new(4.0, -3.0), DVec2::new(4.0, 3.0), DVec2::new(-4.0, 3.0)];
+        let mut walls: Vec<_> = (0..4).map(|i| segment(corners[i], corners[(i + 1) % 4])).collect();
+        walls[1] = Segment { texture: Texture::Glitch(0.5), ..segment(corners[1], corners[2]) };
+        let mut scene = Scene::new(walls);
+        scene.sprites.push(Sprite { pos: DVec2::new(2.0, 0.5), size: 0.5, texture: Texture::Solid(Vec3::new(1.0, 0.2, 0.2)) });
+        let camera = Camera { pos: DVec2::new(-2.0, 0.0), height: Camera::EYE_HEIGHT, rot: 0.0, fov: 66f64.to_radians(), noise: 0.4, fog: Fog::default() };
+        let rng = RngContext::new(7);
+        let frame = |threads| {
+            let mut renderer = Renderer::headless(160, 120);
+            renderer.set_threads(threads);
+            renderer.draw(&scene, &camera, 1.0 / 60.0, &rng);
+            renderer.frame().to_vec()
+        };
+        let single = frame(1);
+        assert!(single.iter().any(|&pixel| pixel != Vec3::ZERO), "nothing was drawn");
+        assert_eq!(single, frame(8));
+    }
 }
@@ -1,6 +1,8 @@
+use std::{ops::Range, path::Path};
+
 use glam::{DVec2, DVec3, Vec3};
 
-use crate::{camera::Ray, texture::Texture};
+use crate::{assets::Assets, camera::{Camera, Ray}, grid::{Grid, Traversal}, level::{self, LevelError}, synth::Patch, texture::{Pattern, Space, Texture}};
 
 pub struct HitData<'a> {
     pub dist: f64,
@@ -12,9 +14,28 @@ pub struct HitData<'a> {
 pub struct Segment {
     pub a: DVec2,
     pub b: DVec2,
+    /// height of the bottom edge
+    pub bottom: f64,
+    /// height of the top edge
+    pub top: f64,
+    /// multiplied with the texture's alpha, below 1 for glass and the like
+    pub opacity: f32,
+    /// fraction of the radiation passing through that the segment stops
+    pub shielding: f64,
     pub texture: Texture,
 }
 impl Segment {
+    pub fn hit(&self, ray: &Ray) -> Option<HitData<'_>> {
+        let (point, u) = self.intersection(ray)?;
+        // along the larger component, a tiny one would blow any error in `point` up
+        let d = point - ray.origin;
+        let dist = if ray.dir.x.abs() > ray.dir.y.abs() {
+            d.x / ray.dir.x
+        } else {
+            d.y / ray.dir.y
+        };
+        (dist > 0.0 && dist.is_finite()).then_some(HitData { dist, point, u, segment: self })
+    }
     pub fn intersection(&self, r: &Ray) -> Option<(DVec2, f64)> {
         // ax + by + c = 0
         let d = self.b - self.a;
@@ -54,36 +75,522 @@ impl Segment {
     }
 }
 
+/// A billboard standing on the floor, always facing the camera.
+pub struct Sprite {
+    pub pos: DVec2,
+    /// width and height
+    pub size: f64,
+    pub texture: Texture,
+}
+
+/// A point giving off radiation that falls off with the square of the distance.
+pub struct RadiationSource {
+    pub pos: DVec2,
+    /// dose at a distance of one unit, with nothing in between
+    pub strength: f64,
+}
+impl RadiationSource {
+    /// distances closer than this count as this, so standing on a source doesn't give an infinite dose
+    pub const MIN_DIST: f64 = 0.1;
+}
+
+/// A light that walls cast shadows from, shining every way unless it's a spot.
+pub struct Light {
+    pub pos: DVec2,
+    pub height: f64,
+    pub color: Vec3,
+    /// brightness right next to the light, halved a unit away
+    pub intensity: f32,
+    /// nothing past this distance is lit, the falloff is smoothed to reach 0 here
+    pub radius: f64,
+    pub spot: Option<Spot>,
+}
+impl Light {
+    /// How bright the light is at `offset` from it, ignoring shadows and which way the surface faces.
+    pub fn brightness(&self, offset: DVec3) -> f32 {
+        let dist_squared = offset.length_squared();
+        let window = (1.0 - dist_squared / (self.radius * self.radius)).max(0.0);
+        let cone = self.spot.as_ref().map_or(1.0, |spot| spot.cone(offset));
+        self.intensity * (window * window * cone / (1.0 + dist_squared)) as f32
+    }
+    /// Light reaching a surface at `offset` from the light before shadows, `None` if none does.
+    fn unshadowed(&self, offset: DVec3, normal: Option<DVec3>) -> Option<Vec3> {
+        if offset.length_squared() >= self.radius * self.radius {
+            return None;
+        }
+        let facing = normal.map_or(1.0, |normal| -offset.normalize_or_zero().dot(normal));
+        if facing <= 0.0 {
+            return None;
+        }
+        let brightness = self.brightness(offset) * facing as f32;
+        (brightness > 0.0).then(|| self.color * brightness)
+    }
+}
+
+/// The walls between each light and one spot on the map, so any height there can be lit
+/// without casting the shadow rays again, e.g. a whole column of a wall. Reused to avoid allocating.
+#[derive(Default)]
+pub struct LightProbe {
+    pos: DVec2,
+    /// per light its walls in `walls`, `None` if the spot is out of its radius
+    lights: Vec<Option<Range<usize>>>,
+    /// how far along the way from the light, as a fraction, the wall's bottom and top, and how much light gets through it
+    walls: Vec<(f64, f64, f64, f32)>,
+}
+
+/// The walls that might shadow a line on the map from each light, e.g. the floor along one column,
+/// so points on it only test those instead of casting rays through the grid.
+#[derive(Default)]
+pub struct LightSweep {
+    lights: Vec<SweptLight>,
+    /// indices into the scene's segments
+    walls: Vec<u32>,
+}
+enum SweptLight {
+    /// the whole line is out of its radius
+    Out,
+    /// only these walls in `walls` can be in the way
+    Walls(Range<usize>),
+    /// too many walls near to be worth listing, each point casts its own ray
+    Grid,
+}
+
+/// Narrows a [`Light`] to a cone pointing horizontally.
+pub struct Spot {
+    pub rot: f64,
+    /// angle from the middle of the cone out to where it starts fading
+    pub inner: f64,
+    /// angle from the middle of the cone out to where it's dark
+    pub outer: f64,
+}
+impl Spot {
+    /// 1 inside the inner cone, fading to 0 at the outer one
+    fn cone(&self, offset: DVec3) -> f64 {
+        let dir = DVec2::from_angle(self.rot).extend(0.0);
+        let angle = dir.angle_between(offset);
+        let t = ((self.outer - angle) / (self.outer - self.inner).max(1e-6)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+/// Something in the world making a sound, like humming machinery or dripping water.
+pub struct Emitter {
+    pub pos: DVec2,
+    pub patch: Patch,
+    pub hz: f64,
+    /// heard at this volume from a unit away
+    pub volume: f32,
+    /// plays a note every this many seconds, held for half of it, instead of one endless note
+    pub interval: Option<f64>,
+}
+
+/// An axis aligned region with its own floor and ceiling.
+///
+/// Heights and textures only change where the renderer hits a wall, so area edges should line up with segments.
+pub struct Area {
+    pub min: DVec2,
+    pub max: DVec2,
+    pub floor: f64,
+    pub ceiling: f64,
+    /// the scene's floor texture if `None`
+    pub floor_texture: Option<Texture>,
+    /// the scene's ceiling texture if `None`
+    pub ceiling_texture: Option<Texture>,
+}
+impl Area {
+    pub fn contains(&self, pos: DVec2) -> bool {
+        pos.cmpge(self.min).all() && pos.cmplt(self.max).all()
+    }
+}
+
+/// The floor and ceiling at some position.
+#[derive(Clone, Copy)]
+pub struct Flats<'a> {
+    pub floor: f64,
+    pub ceiling: f64,
+    /// sampled in world space, uv is the position on the floor
+    pub floor_texture: &'a Texture,
+    pub ceiling_texture: &'a Texture,
+}
+
 pub struct Scene {
-    pub segments: Vec<Segment>
+    /// only changed through methods that keep `grid` in sync with it
+    segments: Vec<Segment>,
+    pub sprites: Vec<Sprite>,
+    pub sources: Vec<RadiationSource>,
+    pub emitters: Vec<Emitter>,
+    pub lights: Vec<Light>,
+    /// light reaching everything regardless of the lights, white unless the level is meant to be dark
+    pub ambient: Vec3,
+    /// later areas take priority where they overlap
+    pub areas: Vec<Area>,
+    /// floor texture outside of any area
+    pub floor_texture: Texture,
+    /// ceiling texture outside of any area
+    pub ceiling_texture: Texture,
+    grid: Grid,
 }
 impl Scene {
-    pub fn sample(&self, ray: &Ray) -> Option<HitData> {
-        let mut closest_texture = &Segment { a: DVec2::ZERO, b: DVec2::ZERO, texture: Texture::Solid(Vec3::ZERO) };
-        let mut closest_u = 0.0;
-        let mut closest = DVec2::ZERO;
-        let mut dist = f64::INFINITY;
-
-        for segment in &self.segments {
-            if let Some((pos, u)) = segment.intersection(ray) {
-                let d = (pos - ray.origin) / ray.dir;
-                let d = if d.x.is_nan() {
-                    d.y
-                } else {
-                    d.x
-                };
-                if d > 0.0 && d < dist {
-                    closest_texture = segment;
-                    closest_u = u;
-                    closest = pos;
-                    dist = d;
+    /// floor height outside of any area
+    pub const FLOOR: f64 = 0.0;
+    /// ceiling height outside of any area
+    pub const CEILING: f64 = 1.0;
+    /// past this many walls near a line, [`Scene::sweep_lights`] leaves each point to cast its own ray
+    const MAX_SWEPT_WALLS: usize = 16;
+    /// and past this many grid cells, so the search itself stays cheap
+    const MAX_SWEPT_CELLS: usize = 64;
+
+    pub fn new(segments: Vec<Segment>) -> Self {
+        let mut scene = Self {
+            segments,
+            sprites: Vec::new(),
+            sources: Vec::new(),
+            emitters: Vec::new(),
+            lights: Vec::new(),
+            ambient: Vec3::ONE,
+            areas: Vec::new(),
+            floor_texture: Texture::Pattern(Pattern::Checker { a: Vec3::ZERO, b: Vec3::splat(0.2), tile: 1.0 }, Space::World),
+            ceiling_texture: Texture::Pattern(Pattern::Checker { a: Vec3::ZERO, b: Vec3::splat(0.2), tile: 1.0 }, Space::World),
+            grid: Grid::default(),
+        };
+        scene.rebuild();
+        scene
+    }
+    /// Loads a scene and its starting camera from a level file, see [`crate::level`]
+    pub fn load(path: impl AsRef<Path>) -> Result<(Scene, Camera), LevelError> {
+        level::load(path.as_ref(), &mut Assets::new())
+    }
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+    pub fn add_segment(&mut self, segment: Segment) {
+        self.segments.push(segment);
+        self.rebuild();
+    }
+    pub fn remove_segment(&mut self, index: usize) -> Segment {
+        let segment = self.segments.remove(index);
+        self.rebuild();
+        segment
+    }
+    /// Changes the segments in any way at once, rebuilding the acceleration grid only once afterwards.
+    pub fn edit_segments<T>(&mut self, edit: impl FnOnce(&mut Vec<Segment>) -> T) -> T {
+        let result = edit(&mut self.segments);
+        self.rebuild();
+        result
+    }
+    /// The grid stores indices into `segments`, so it has to be rebuilt whenever they change.
+    fn rebuild(&mut self) {
+        self.grid = Grid::new(&self.segments);
+    }
+    pub fn area_at(&self, pos: DVec2) -> Option<&Area> {
+        self.areas.iter().rev().find(|area| area.contains(pos))
+    }
+    pub fn floor_at(&self, pos: DVec2) -> f64 {
+        self.area_at(pos).map_or(Self::FLOOR, |area| area.floor)
+    }
+    pub fn ceiling_at(&self, pos: DVec2) -> f64 {
+        self.area_at(pos).map_or(Self::CEILING, |area| area.ceiling)
+    }
+    pub fn flats_at(&self, pos: DVec2) -> Flats<'_> {
+        let area = self.area_at(pos);
+        Flats {
+            floor: area.map_or(Self::FLOOR, |area| area.floor),
+            ceiling: area.map_or(Self::CEILING, |area| area.ceiling),
+            floor_texture: area.and_then(|area| area.floor_texture.as_ref()).unwrap_or(&self.floor_texture),
+            ceiling_texture: area.and_then(|area| area.ceiling_texture.as_ref()).unwrap_or(&self.ceiling_texture),
+        }
+    }
+    /// Segments that might overlap the box, a cheap broad phase for things like collision.
+    pub fn segments_near(&self, min: DVec2, max: DVec2) -> impl Iterator<Item = &Segment> {
+        self.grid.query(min, max).into_iter().map(|i| &self.segments[i as usize])
+    }
+    /// Radiation dose at the position from every source, shielded by the segments in the way.
+    pub fn dose_at(&self, pos: DVec2) -> f64 {
+        self.sources.iter().map(|source| {
+            let ray = Ray { origin: pos, dir: source.pos - pos };
+            let transmitted: f64 = self.hits(&ray)
+                .take_while(|hit| hit.dist < 1.0)
+                .map(|hit| 1.0 - hit.segment.shielding)
+                .product();
+            source.strength * transmitted / ray.dir.length_squared().max(RadiationSource::MIN_DIST * RadiationSource::MIN_DIST)
+        }).sum()
+    }
+    /// Light falling on `pos` from the ambient light and every light not blocked by a wall.
+    /// `normal` faces out of the surface, sprites and other things without one are lit from every side.
+    pub fn light_at(&self, pos: DVec3, normal: Option<DVec3>) -> Vec3 {
+        self.lights.iter().fold(self.ambient, |total, light| {
+            let from = light.pos.extend(light.height);
+            match light.unshadowed(pos - from, normal) {
+                Some(lit) => total + lit * self.transmittance(from, pos),
+                None => total,
+            }
+        })
+    }
+    /// Finds the walls between every light and `pos` for [`Scene::probed_light_at`].
+    pub fn probe_lights(&self, pos: DVec2, probe: &mut LightProbe) {
+        probe.pos = pos;
+        probe.lights.clear();
+        probe.walls.clear();
+        for light in &self.lights {
+            let dir = pos - light.pos;
+            // any height only takes it further away
+            if dir.length_squared() >= light.radius * light.radius {
+                probe.lights.push(None);
+                continue;
+            }
+            let start = probe.walls.len();
+            let ray = Ray { origin: light.pos, dir };
+            // the surface being lit doesn't shadow itself
+            let walls = self.hits(&ray).take_while(|hit| hit.dist < 1.0 - 1e-6);
+            probe.walls.extend(walls.map(|hit| (hit.dist, hit.segment.bottom, hit.segment.top, 1.0 - hit.segment.opacity.clamp(0.0, 1.0))));
+            probe.lights.push(Some(start..probe.walls.len()));
+        }
+    }
+    /// [`Scene::light_at`] at height `z` above the probed spot, the same light without casting any rays.
+    pub fn probed_light_at(&self, probe: &LightProbe, z: f64, normal: Option<DVec3>) -> Vec3 {
+        let pos = probe.pos.extend(z);
+        self.lights.iter().zip(&probe.lights).fold(self.ambient, |total, (light, walls)| {
+            let Some(walls) = walls.clone() else {
+                return total;
+            };
+            let from = light.pos.extend(light.height);
+            let Some(lit) = light.unshadowed(pos - from, normal) else {
+                return total;
+            };
+            let transmittance: f32 = probe.walls[walls].iter()
+                .filter(|&&(dist, bottom, top, _)| (bottom..top).contains(&(from.z + (z - from.z) * dist)))
+                .map(|&(.., through)| through)
+                .product();
+            total + lit * transmittance
+        })
+    }
+    /// Finds the walls that might shadow the line from `a` to `b` for [`Scene::swept_light_at`].
+    pub fn sweep_lights(&self, a: DVec2, b: DVec2, sweep: &mut LightSweep) {
+        sweep.lights.clear();
+        sweep.walls.clear();
+        let d = b - a;
+        for light in &self.lights {
+            let t = ((light.pos - a).dot(d) / d.length_squared()).clamp(0.0, 1.0);
+            let closest = if t.is_nan() { a } else { a + d * t };
+            if closest.distance_squared(light.pos) >= light.radius * light.radius {
+                sweep.lights.push(SweptLight::Out);
+                continue;
+            }
+            // every shadow ray stays inside the triangle between the light and the line
+            let start = sweep.walls.len();
+            let (min, max) = (light.pos.min(a).min(b), light.pos.max(a).max(b));
+            let listed = self.grid.query_into(min, max, Self::MAX_SWEPT_CELLS, &mut sweep.walls);
+            sweep.lights.push(if listed && sweep.walls.len() - start <= Self::MAX_SWEPT_WALLS {
+                SweptLight::Walls(start..sweep.walls.len())
+            } else {
+                sweep.walls.truncate(start);
+                SweptLight::Grid
+            });
+        }
+    }
+    /// [`Scene::light_at`] for a point on the swept line, testing only the walls found for it.
+    pub fn swept_light_at(&self, sweep: &LightSweep, pos: DVec3, normal: Option<DVec3>) -> Vec3 {
+        self.lights.iter().zip(&sweep.lights).fold(self.ambient, |total, (light, walls)| {
+            if let SweptLight::Out = walls {
+                return total;
+            }
+            let from = light.pos.extend(light.height);
+            let Some(lit) = light.unshadowed(pos - from, normal) else {
+                return total;
+            };
+            let transmittance = match walls {
+                SweptLight::Walls(walls) => {
+                    let ray = Ray { origin: from.truncate(), dir: (pos - from).truncate() };
+                    sweep.walls[walls.clone()].iter()
+                        .filter_map(|&i| self.segments[i as usize].hit(&ray))
+                        .filter(|hit| hit.dist < 1.0 - 1e-6)
+                        .map(|hit| through(from, pos, &hit))
+                        .product()
+                }
+                _ => self.transmittance(from, pos),
+            };
+            total + lit * transmittance
+        })
+    }
+    /// How much light gets from `from` to `to`, with opaque walls in between stopping all of it.
+    fn transmittance(&self, from: DVec3, to: DVec3) -> f32 {
+        let ray = Ray { origin: from.truncate(), dir: (to - from).truncate() };
+        self.hits(&ray)
+            // the surface being lit doesn't shadow itself
+            .take_while(|hit| hit.dist < 1.0 - 1e-6)
+            .map(|hit| through(from, to, &hit))
+            .product()
+    }
+    /// the closest hit
+    pub fn sample(&self, ray: &Ray) -> Option<HitData<'_>> {
+        self.hits(ray).next()
+    }
+    /// every hit, closest first
+    pub fn sample_all(&self, ray: &Ray) -> Vec<HitData<'_>> {
+        self.hits(ray).collect()
+    }
+    /// Lazily finds every hit along the ray, closest first, so callers can stop early.
+    pub fn hits(&self, ray: &Ray) -> Hits<'_> {
+        Hits { scene: self, ray: *ray, cells: self.grid.traverse(ray), pending: Vec::new() }
+    }
+}
+
+/// How much light on its way from `from` to `to` gets through the wall it hits, all of it if it passes over or under.
+fn through(from: DVec3, to: DVec3, hit: &HitData) -> f32 {
+    let z = from.z + (to.z - from.z) * hit.dist;
+    if (hit.segment.bottom..hit.segment.top).contains(&z) {
+        1.0 - hit.segment.opacity.clamp(0.0, 1.0)
+    } else {
+        1.0
+    }
+}
+
+/// Iterator returned by [`Scene::hits`].
+pub struct Hits<'a> {
+    scene: &'a Scene,
+    ray: Ray,
+    cells: Traversal<'a>,
+    /// hits in the current cell, furthest first
+    pending: Vec<HitData<'a>>,
+}
+impl<'a> Iterator for Hits<'a> {
+    type Item = HitData<'a>;
+
+    fn next(&mut self) -> Option<HitData<'a>> {
+        while self.pending.is_empty() {
+            let (cell, enter, exit) = self.cells.next()?;
+            for &i in cell {
+                if let Some(hit) = self.scene.segments[i as usize].hit(&self.ray) {
+                    // segments span several cells, only keep hits inside this one so each is found once
+                    if hit.dist > enter && hit.dist <= exit {
+                        self.pending.push(hit);
+                    }
                 }
             }
+            self.pending.sort_by(|a, b| b.dist.total_cmp(&a.dist));
         }
+        self.pending.pop()
+    }
+}
 
-        if dist.is_infinite() {
-            return None;
+#[cfg(test)]
+pub(crate) mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::random::GameRng;
+
+    /// a full height white wall, for building scenes in tests
+    pub(crate) fn segment(a: DVec2, b: DVec2) -> Segment {
+        Segment { a, b, bottom: Scene::FLOOR, top: Scene::CEILING, opacity: 1.0, shielding: 0.5, texture: Texture::Solid(Vec3::ONE) }
+    }
+
+    fn random_scene(rng: &mut GameRng) -> Scene {
+        let segments = (0..200).map(|_| {
+            let a = DVec2::new(rng.random_range(-20.0..20.0), rng.random_range(-20.0..20.0));
+            let b = a + DVec2::new(rng.random_range(-4.0..4.0), rng.random_range(-4.0..4.0));
+            segment(a, b)
+        }).collect();
+        Scene::new(segments)
+    }
+
+    /// hits as distances and segment indices, closest first
+    fn sorted<'a>(scene: &Scene, hits: impl Iterator<Item = HitData<'a>>) -> Vec<(f64, usize)> {
+        let index = |segment: &Segment| scene.segments().iter().position(|other| std::ptr::eq(other, segment)).unwrap();
+        let mut hits: Vec<_> = hits.map(|hit| (hit.dist, index(hit.segment))).collect();
+        // the grid only promises distance order, segments hit at the same distance can come either way
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        hits
+    }
+
+    /// every hit from testing each segment
+    fn brute_force(scene: &Scene, ray: &Ray) -> Vec<(f64, usize)> {
+        sorted(scene, scene.segments().iter().filter_map(|segment| segment.hit(ray)))
+    }
+
+    fn grid(scene: &Scene, ray: &Ray) -> Vec<(f64, usize)> {
+        sorted(scene, scene.hits(ray))
+    }
+
+    #[test]
+    fn grid_matches_linear_scan() {
+        let mut rng = GameRng::seed_from_u64(3);
+        let scene = random_scene(&mut rng);
+        for _ in 0..2000 {
+            let origin = DVec2::new(rng.random_range(-25.0..25.0), rng.random_range(-25.0..25.0));
+            let ray = Ray { origin, dir: DVec2::from_angle(rng.random_range(0.0..std::f64::consts::TAU)) };
+            assert_eq!(grid(&scene, &ray), brute_force(&scene, &ray), "ray {:?} {:?}", ray.origin, ray.dir);
+        }
+    }
+
+    #[test]
+    fn grid_matches_linear_scan_along_the_axes() {
+        let mut rng = GameRng::seed_from_u64(4);
+        let scene = random_scene(&mut rng);
+        for _ in 0..500 {
+            let origin = DVec2::new(rng.random_range(-25.0..25.0), rng.random_range(-25.0..25.0));
+            // `from_angle` leaves the other component tiny rather than 0
+            let angle = rng.random_range(0..4) as f64 * std::f64::consts::FRAC_PI_2;
+            for dir in [DVec2::from_angle(angle), DVec2::from_angle(angle).round()] {
+                let ray = Ray { origin, dir };
+                assert_eq!(grid(&scene, &ray), brute_force(&scene, &ray), "ray {:?} {:?}", ray.origin, ray.dir);
+            }
         }
-        Some(HitData { dist, point: closest, u: closest_u, segment: closest_texture })
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn tiny_direction_components_are_not_divided_by() {
+        // a long corridor, so the grid's cells are far bigger than the corridor is wide
+        let scene = Scene::new(vec![
+            segment(DVec2::new(1000.0, 0.5), DVec2::new(-1000.0, 0.5)),
+            segment(DVec2::new(-1000.0, -0.5), DVec2::new(1000.0, -0.5)),
+        ]);
+        // `from_angle` leaves x at about 1e-16, measuring the distance along it is garbage
+        let ray = Ray { origin: DVec2::new(1.0, 0.0), dir: DVec2::from_angle(270f64.to_radians()) };
+        let hit = scene.sample(&ray).expect("the side wall should be hit");
+        assert!((hit.dist - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn probed_and_swept_light_match_casting_rays() {
+        let mut rng = GameRng::seed_from_u64(5);
+        let mut scene = random_scene(&mut rng);
+        // low and high walls light passes over or under, and translucent ones
+        scene.edit_segments(|segments| for segment in segments {
+            segment.bottom = rng.random_range(-0.5..0.5);
+            segment.top = segment.bottom + rng.random_range(0.1..1.5);
+            segment.opacity = rng.random_range(0.2..1.0);
+        });
+        for _ in 0..8 {
+            let pos = DVec2::new(rng.random_range(-20.0..20.0), rng.random_range(-20.0..20.0));
+            scene.lights.push(Light { pos, height: rng.random_range(0.0..1.0), color: Vec3::ONE, intensity: 2.0, radius: 12.0, spot: None });
+        }
+        let close = |a: Vec3, b: Vec3| (a - b).abs().max_element() < 1e-5;
+        let (mut probe, mut sweep) = (LightProbe::default(), LightSweep::default());
+        for _ in 0..200 {
+            let pos = DVec2::new(rng.random_range(-20.0..20.0), rng.random_range(-20.0..20.0));
+            let normal = Some(DVec2::from_angle(rng.random_range(0.0..std::f64::consts::TAU)).extend(0.0));
+            scene.probe_lights(pos, &mut probe);
+            let end = pos + DVec2::new(rng.random_range(-3.0..3.0), rng.random_range(-3.0..3.0));
+            scene.sweep_lights(pos, end, &mut sweep);
+            for _ in 0..10 {
+                let z = rng.random_range(-0.5..1.5);
+                assert!(close(scene.probed_light_at(&probe, z, normal), scene.light_at(pos.extend(z), normal)));
+                let on_line = pos.lerp(end, rng.random_range(0.0..=1.0)).extend(z);
+                assert!(close(scene.swept_light_at(&sweep, on_line, Some(DVec3::Z)), scene.light_at(on_line, Some(DVec3::Z))));
+            }
+        }
+    }
+
+    #[test]
+    fn editing_segments_rebuilds_the_grid() {
+        let mut scene = Scene::new(vec![segment(DVec2::new(1.0, -1.0), DVec2::new(1.0, 1.0)), segment(DVec2::new(2.0, -1.0), DVec2::new(2.0, 1.0))]);
+        let ray = Ray { origin: DVec2::new(0.0, 0.1), dir: DVec2::new(1.0, 0.01) };
+        scene.remove_segment(0);
+        assert_eq!(grid(&scene, &ray), brute_force(&scene, &ray));
+        scene.add_segment(segment(DVec2::new(30.0, -1.0), DVec2::new(30.0, 1.0)));
+        assert_eq!(scene.sample_all(&ray).len(), 2);
+        scene.edit_segments(|segments| segments.clear());
+        assert!(scene.sample(&ray).is_none());
+    }
+}
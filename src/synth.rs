@@ -0,0 +1,437 @@
+use std::{f64::consts::TAU, sync::{atomic::{AtomicU64, Ordering}, mpsc::{self, Receiver, Sender}, Arc}};
+
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::random::GameRng;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum WaveType {
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+    /// high for this fraction of each period
+    Pulse(f64),
+    WhiteNoise,
+    /// -3 dB per octave
+    PinkNoise,
+    /// -6 dB per octave
+    BrownNoise,
+}
+
+/// Attack, decay and release times in seconds, sustain as a fraction of the peak.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Envelope {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+}
+impl Envelope {
+    /// full volume from note on to note off
+    pub const GATE: Self = Self { attack: 0.0, decay: 0.0, sustain: 1.0, release: 0.0 };
+}
+
+/// A sine wave modulating some parameter of a voice.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lfo {
+    pub hz: f64,
+    pub depth: f64,
+}
+
+/// Frequency modulation by a sine wave at a multiple of the note's frequency.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fm {
+    pub ratio: f64,
+    /// peak phase deviation in radians, higher is brighter
+    pub index: f64,
+}
+
+/// Everything about how a note sounds except its pitch and volume.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Patch {
+    pub wave: WaveType,
+    pub envelope: Envelope,
+    pub fm: Option<Fm>,
+    /// depth in semitones
+    pub vibrato: Option<Lfo>,
+    /// depth as a fraction of the volume
+    pub tremolo: Option<Lfo>,
+    pub channel: Channel,
+}
+impl Patch {
+    /// a plain gated wave on the effects channel
+    pub const fn new(wave: WaveType) -> Self {
+        Self { wave, envelope: Envelope::GATE, fm: None, vibrato: None, tremolo: None, channel: Channel::Effects }
+    }
+}
+
+/// Mixer channels, each with its own volume.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Ambience,
+    Effects,
+    Music,
+}
+impl Channel {
+    const COUNT: usize = 3;
+}
+
+/// Where a voice is heard from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Placement {
+    /// -1 is only the left speaker, 1 only the right
+    pub pan: f32,
+    /// 0 is clear, 1 is heard through a wall
+    pub muffle: f32,
+}
+impl Placement {
+    pub const CENTER: Self = Self { pan: 0.0, muffle: 0.0 };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VoiceId(u64);
+
+#[derive(Debug)]
+enum Command {
+    NoteOn { id: VoiceId, patch: Patch, hz: f64, volume: f32, length: Option<f64>, placement: Placement },
+    NoteOff(VoiceId),
+    SetVolume(VoiceId, f32),
+    SetFrequency(VoiceId, f64),
+    SetPlacement(VoiceId, Placement),
+    SetChannelVolume(Channel, f32),
+    SetMasterVolume(f32),
+    StopAll,
+}
+
+/// Controls a [`Synth`] from other threads, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct SynthHandle {
+    commands: Sender<Command>,
+    next_id: Arc<AtomicU64>,
+}
+impl SynthHandle {
+    /// Starts a note that holds until [`SynthHandle::note_off`].
+    pub fn note_on(&self, patch: Patch, hz: f64, volume: f32, placement: Placement) -> VoiceId {
+        let id = VoiceId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.send(Command::NoteOn { id, patch, hz, volume, length: None, placement });
+        id
+    }
+    /// Plays a note that releases itself after `length` seconds, for sound effects.
+    pub fn play(&self, patch: Patch, hz: f64, volume: f32, length: f64, placement: Placement) -> VoiceId {
+        let id = VoiceId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.send(Command::NoteOn { id, patch, hz, volume, length: Some(length), placement });
+        id
+    }
+    /// Starts the release of the note, it keeps sounding for the envelope's release time.
+    pub fn note_off(&self, id: VoiceId) {
+        self.send(Command::NoteOff(id));
+    }
+    pub fn set_volume(&self, id: VoiceId, volume: f32) {
+        self.send(Command::SetVolume(id, volume));
+    }
+    pub fn set_frequency(&self, id: VoiceId, hz: f64) {
+        self.send(Command::SetFrequency(id, hz));
+    }
+    pub fn set_placement(&self, id: VoiceId, placement: Placement) {
+        self.send(Command::SetPlacement(id, placement));
+    }
+    pub fn set_channel_volume(&self, channel: Channel, volume: f32) {
+        self.send(Command::SetChannelVolume(channel, volume));
+    }
+    pub fn set_master_volume(&self, volume: f32) {
+        self.send(Command::SetMasterVolume(volume));
+    }
+    /// Silences every voice immediately, skipping their release.
+    pub fn stop_all(&self) {
+        self.send(Command::StopAll);
+    }
+    fn send(&self, command: Command) {
+        // the synth only goes away with the audio device, after which there's nothing left to hear it
+        let _ = self.commands.send(command);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    /// falling by this much per second
+    Release(f64),
+    Done,
+}
+
+#[derive(Debug)]
+struct Voice {
+    id: VoiceId,
+    patch: Patch,
+    hz: f64,
+    volume: f32,
+    /// seconds since note on
+    age: f64,
+    /// released once `age` passes this
+    length: Option<f64>,
+    stage: Stage,
+    level: f64,
+    placement: Placement,
+    /// output of the muffling low pass filter
+    muffled: f64,
+    /// position in the current period, from 0 to 1
+    phase: f64,
+    fm_phase: f64,
+    vibrato_phase: f64,
+    tremolo_phase: f64,
+    /// pink noise filter state
+    pink: [f64; 3],
+    /// brown noise integrator state
+    brown: f64,
+}
+impl Voice {
+    /// cutoff of the low pass filter when fully muffled
+    const MUFFLED_HZ: f64 = 400.0;
+    /// cutoff as the muffle approaches 0, where the filter is bypassed
+    const CLEAR_HZ: f64 = 18000.0;
+
+    fn new(id: VoiceId, patch: Patch, hz: f64, volume: f32, length: Option<f64>, placement: Placement) -> Self {
+        Self { id, patch, hz, volume, age: 0.0, length, stage: Stage::Attack, level: 0.0, placement, muffled: 0.0, phase: 0.0, fm_phase: 0.0, vibrato_phase: 0.0, tremolo_phase: 0.0, pink: [0.0; 3], brown: 0.0 }
+    }
+    fn release(&mut self) {
+        let release = self.patch.envelope.release;
+        self.stage = if self.level <= 0.0 {
+            Stage::Done
+        } else if release > 0.0 {
+            Stage::Release(self.level / release)
+        } else {
+            Stage::Done
+        };
+    }
+    fn envelope(&mut self, dt: f64) -> f64 {
+        let Envelope { attack, decay, sustain, .. } = self.patch.envelope;
+        match self.stage {
+            Stage::Attack => {
+                self.level += if attack > 0.0 { dt / attack } else { 1.0 };
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= if decay > 0.0 { dt * (1.0 - sustain) / decay } else { 1.0 };
+                if self.level <= sustain {
+                    self.level = sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => {}
+            Stage::Release(rate) => {
+                self.level -= rate * dt;
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Done;
+                }
+            }
+            Stage::Done => self.level = 0.0,
+        }
+        self.level
+    }
+    /// The current sample, then advances the phases by `dt` seconds.
+    fn oscillate(&mut self, dt: f64, rng: &mut GameRng) -> f64 {
+        // frequency changes only change how fast the phase moves, so they never jump
+        let mut hz = self.hz;
+        if let Some(vibrato) = self.patch.vibrato {
+            hz *= 2f64.powf(vibrato.depth * (TAU * self.vibrato_phase).sin() / 12.0);
+            advance(&mut self.vibrato_phase, vibrato.hz * dt);
+        }
+        if let Some(fm) = self.patch.fm {
+            hz += fm.index * fm.ratio * self.hz * (TAU * self.fm_phase).cos();
+            advance(&mut self.fm_phase, fm.ratio * self.hz * dt);
+        }
+        let phase = self.phase;
+        // fraction of a period per sample, for smoothing the discontinuities
+        let step = (hz * dt).abs().min(0.5);
+        advance(&mut self.phase, hz * dt);
+        match self.patch.wave {
+            WaveType::Sine => (TAU * phase).sin(),
+            WaveType::Square => square(phase, 0.5, step),
+            WaveType::Saw => 2.0 * phase - 1.0 - poly_blep(phase, step),
+            WaveType::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            WaveType::Pulse(width) => square(phase, width, step),
+            WaveType::WhiteNoise => rng.random_range(-1.0..=1.0),
+            WaveType::PinkNoise => {
+                // Paul Kellet's economy filter
+                let white = rng.random_range(-1.0..=1.0);
+                let [b0, b1, b2] = &mut self.pink;
+                *b0 = 0.99765 * *b0 + white * 0.0990460;
+                *b1 = 0.96300 * *b1 + white * 0.2965164;
+                *b2 = 0.57000 * *b2 + white * 1.0526913;
+                (*b0 + *b1 + *b2 + white * 0.1848) * 0.25
+            }
+            WaveType::BrownNoise => {
+                let white = rng.random_range(-1.0..=1.0);
+                self.brown = (self.brown + 0.02 * white) / 1.02;
+                self.brown * 3.5
+            }
+        }
+    }
+    /// The next left and right samples.
+    fn next(&mut self, dt: f64, rng: &mut GameRng) -> [f32; 2] {
+        if self.length.is_some_and(|length| self.age >= length) && !matches!(self.stage, Stage::Release(_) | Stage::Done) {
+            self.release();
+        }
+        let envelope = self.envelope(dt);
+        let mut sample = self.oscillate(dt, rng) * envelope;
+        if let Some(tremolo) = self.patch.tremolo {
+            sample *= 1.0 - tremolo.depth * (0.5 + 0.5 * (TAU * self.tremolo_phase).sin());
+            advance(&mut self.tremolo_phase, tremolo.hz * dt);
+        }
+        self.age += dt;
+
+        let muffle = self.placement.muffle.clamp(0.0, 1.0) as f64;
+        if muffle > 0.0 {
+            let cutoff = Self::CLEAR_HZ * (Self::MUFFLED_HZ / Self::CLEAR_HZ).powf(muffle);
+            self.muffled += (sample - self.muffled) * (1.0 - (-TAU * cutoff * dt).exp());
+        } else {
+            self.muffled = sample;
+        }
+        // balance rather than constant power panning, so centred voices sound as they did in mono
+        let pan = self.placement.pan.clamp(-1.0, 1.0);
+        let sample = self.muffled as f32 * self.volume;
+        [sample * (1.0 - pan).min(1.0), sample * (1.0 + pan).min(1.0)]
+    }
+}
+
+fn advance(phase: &mut f64, by: f64) {
+    *phase = (*phase + by).rem_euclid(1.0);
+}
+
+/// Band limited pulse wave, high for `width` of each period.
+fn square(phase: f64, width: f64, step: f64) -> f64 {
+    let naive = if phase < width { 1.0 } else { -1.0 };
+    naive + poly_blep(phase, step) - poly_blep((phase + 1.0 - width).rem_euclid(1.0), step)
+}
+
+/// Correction for a unit step down at phase 0, rounding off the corner that would otherwise alias.
+/// `step` is how far the phase moves per sample.
+fn poly_blep(phase: f64, step: f64) -> f64 {
+    if step <= 0.0 {
+        0.0
+    } else if phase < step {
+        let t = phase / step;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - step {
+        let t = (phase - 1.0) / step;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// Polyphonic synth run on the audio thread, controlled through a [`SynthHandle`].
+#[derive(Debug)]
+pub struct Synth {
+    commands: Receiver<Command>,
+    next_id: Arc<AtomicU64>,
+    voices: Vec<Voice>,
+    channels: [f32; Channel::COUNT],
+    master: f32,
+    rng: GameRng,
+}
+impl Synth {
+    /// past this the oldest voice that's ending anyway is cut off to make room, or the new note is dropped if every voice is held
+    pub const MAX_VOICES: usize = 32;
+
+    pub fn new(rng: GameRng) -> (Self, SynthHandle) {
+        let (sender, commands) = mpsc::channel();
+        let next_id = Arc::new(AtomicU64::new(0));
+        let synth = Self { commands, next_id: next_id.clone(), voices: Vec::new(), channels: [1.0; Channel::COUNT], master: 1.0, rng };
+        (synth, SynthHandle { commands: sender, next_id })
+    }
+    /// Starts a note from the audio thread itself, see [`SynthHandle::note_on`].
+    pub fn note_on(&mut self, patch: Patch, hz: f64, volume: f32, placement: Placement) -> VoiceId {
+        let id = VoiceId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.start(Voice::new(id, patch, hz, volume, None, placement));
+        id
+    }
+    pub fn set_volume(&mut self, id: VoiceId, volume: f32) {
+        if let Some(voice) = self.voice(id) {
+            voice.volume = volume;
+        }
+    }
+    fn start(&mut self, voice: Voice) {
+        if self.voices.len() >= Self::MAX_VOICES {
+            // only notes that are ending anyway go, so held drones don't vanish under a burst of effects
+            let Some(oldest) = self.voices.iter()
+                .position(|voice| voice.length.is_some() || matches!(voice.stage, Stage::Release(_) | Stage::Done))
+            else {
+                return;
+            };
+            self.voices.remove(oldest);
+        }
+        self.voices.push(voice);
+    }
+    fn voice(&mut self, id: VoiceId) -> Option<&mut Voice> {
+        self.voices.iter_mut().find(|voice| voice.id == id)
+    }
+    /// Applies the commands sent since the last call, call it once per buffer.
+    pub fn update(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::NoteOn { id, patch, hz, volume, length, placement } => self.start(Voice::new(id, patch, hz, volume, length, placement)),
+                Command::NoteOff(id) => if let Some(voice) = self.voice(id) {
+                    voice.release();
+                },
+                Command::SetVolume(id, volume) => self.set_volume(id, volume),
+                Command::SetFrequency(id, hz) => if let Some(voice) = self.voice(id) {
+                    voice.hz = hz;
+                },
+                Command::SetPlacement(id, placement) => if let Some(voice) = self.voice(id) {
+                    voice.placement = placement;
+                },
+                Command::SetChannelVolume(channel, volume) => self.channels[channel as usize] = volume,
+                Command::SetMasterVolume(volume) => self.master = volume,
+                Command::StopAll => self.voices.clear(),
+            }
+        }
+    }
+    /// Advances every voice by `dt` seconds and returns their left and right mix.
+    pub fn next(&mut self, dt: f64) -> [f32; 2] {
+        let mut mix = [0.0; 2];
+        for voice in &mut self.voices {
+            let [left, right] = voice.next(dt, &mut self.rng);
+            let volume = self.channels[voice.patch.channel as usize];
+            mix[0] += left * volume;
+            mix[1] += right * volume;
+        }
+        self.voices.retain(|voice| voice.stage != Stage::Done);
+        mix.map(|sample| sample * self.master)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn held_voices_are_never_evicted() {
+        let (mut synth, handle) = Synth::new(GameRng::seed_from_u64(0));
+        let held: Vec<_> = (0..Synth::MAX_VOICES).map(|_| synth.note_on(Patch::new(WaveType::Sine), 440.0, 1.0, Placement::CENTER)).collect();
+        handle.note_on(Patch::new(WaveType::Saw), 220.0, 1.0, Placement::CENTER);
+        handle.play(Patch::new(WaveType::Square), 330.0, 1.0, 0.1, Placement::CENTER);
+        synth.update();
+        assert!(held.iter().all(|&id| synth.voices.iter().any(|voice| voice.id == id)));
+
+        // an effect past the limit takes the place of one that's ending
+        handle.note_off(held[3]);
+        let effect = handle.play(Patch::new(WaveType::Square), 330.0, 1.0, 0.1, Placement::CENTER);
+        synth.update();
+        assert!(synth.voice(held[3]).is_none());
+        assert!(synth.voice(effect).is_some());
+    }
+}
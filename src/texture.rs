@@ -1,54 +1,263 @@
-use bmp::Image;
-use glam::{DVec2, Vec3};
+use std::{f64::consts::TAU, str::FromStr, sync::Arc};
+
+use glam::{DVec2, Vec3, Vec4};
 use rand::Rng;
+use serde::Deserialize;
+
+use crate::{assets::Image, noise};
 
 #[derive(PartialEq)]
 pub enum Texture {
     Solid(Vec3),
-    Stretch(Image),
-    Repeat(Image),
+    Stretch(Arc<Image>),
+    Repeat(Arc<Image>),
     Glitch(f64),
     Compound(Box<Texture>, Box<Texture>, BlendMode),
+    /// the third texture's luminance picks between the first at 0 and the second at 1
+    Mask(Box<Texture>, Box<Texture>, Box<Texture>),
+    /// texels of the key color are transparent
+    Cutout(Box<Texture>, Vec3),
+    /// computed per sample instead of read from an image
+    Pattern(Pattern, Space),
+    /// cycles through the frames at the given frames per second
+    Flipbook(Vec<Texture>, f64),
+    /// moves by the velocity in uv per second
+    Scroll(Box<Texture>, DVec2),
+    /// fades towards the color and back at the given frequency
+    Pulse(Box<Texture>, Vec3, f64),
+}
+/// How images are filtered.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// blocky, and shimmers in the distance. The default, for the look the game has always had
+    #[default]
+    Nearest,
+    Bilinear,
+    /// bilinear between the two closest mips
+    Trilinear,
+}
+impl FromStr for Filter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "nearest" => Ok(Filter::Nearest),
+            "bilinear" => Ok(Filter::Bilinear),
+            "trilinear" => Ok(Filter::Trilinear),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Everything about a sample besides where it is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleContext {
+    /// the surface's size in world units, used by [`Texture::Repeat`] to tile once per unit
+    pub size: DVec2,
+    /// world units covered by one screen pixel at the sample
+    pub footprint: f64,
+    pub filter: Filter,
+    /// seconds on the game clock, for animated textures
+    pub time: f64,
 }
+
 impl Texture {
-    pub fn sample(&self, uv: DVec2, width: f64, rng: &mut impl Rng) -> Vec3 {
+    pub fn sample(&self, uv: DVec2, context: &SampleContext, rng: &mut impl Rng) -> Vec3 {
+        self.sample_rgba(uv, context, rng).truncate()
+    }
+    /// like [`Texture::sample`] with alpha in `w`
+    pub fn sample_rgba(&self, uv: DVec2, context: &SampleContext, rng: &mut impl Rng) -> Vec4 {
+        let size = context.size;
         match self {
-            Texture::Solid(vec3) => *vec3,
-            Texture::Stretch(image) => {
-                let texture_coords = uv * DVec2::new(image.get_width() as f64, image.get_height() as f64);
-                let x = texture_coords.x as u32;
-                let y = texture_coords.y as u32;
-                let color = image.get_pixel(x, y);
-                Vec3::new(color.r as f32 / 255.0, color.g as f32 / 255.0, color.b as f32 / 255.0)
-            },
-            Texture::Repeat(image) => {
-                let texture_coords = (DVec2::new(uv.x * width, uv.y) % DVec2::ONE) *  DVec2::new(image.get_width() as f64, image.get_height() as f64);
-                let x = texture_coords.x as u32;
-                let y = texture_coords.y as u32;
-                let color = image.get_pixel(x, y);
-                Vec3::new(color.r as f32 / 255.0, color.g as f32 / 255.0, color.b as f32 / 255.0)
-            }
+            Texture::Solid(vec3) => vec3.extend(1.0),
+            Texture::Stretch(image) => image.sample(uv, false, context.filter, context.footprint / size),
+            Texture::Repeat(image) => image.sample(uv * size, true, context.filter, DVec2::splat(context.footprint)),
             Texture::Compound(a, b, blend) => {
-                blend.blend(a.sample(uv, width, rng), b.sample(uv, width, rng))
+                let a = a.sample_rgba(uv, context, rng);
+                let b = b.sample_rgba(uv, context, rng);
+                blend.blend(a.truncate(), b.truncate()).extend(a.w * b.w)
+            }
+            Texture::Mask(a, b, mask) => {
+                let t = luminance(mask.sample(uv, context, rng)).clamp(0.0, 1.0);
+                a.sample_rgba(uv, context, rng).lerp(b.sample_rgba(uv, context, rng), t)
+            }
+            Texture::Glitch(amount) => Vec3::splat(rng.random::<f32>().powi(3) * (*amount) as f32).extend(1.0),
+            Texture::Pattern(pattern, space) => {
+                let p = match space {
+                    Space::Uv => uv,
+                    Space::World => uv * size,
+                };
+                pattern.sample(p, context.time).extend(1.0)
+            }
+            Texture::Flipbook(frames, fps) => {
+                let frame = (context.time * fps).floor().rem_euclid(frames.len() as f64) as usize;
+                frames.get(frame).map_or(Vec4::ZERO, |frame| frame.sample_rgba(uv, context, rng))
+            }
+            Texture::Scroll(texture, velocity) => texture.sample_rgba(uv + *velocity * context.time, context, rng),
+            Texture::Pulse(texture, color, hz) => {
+                let t = (0.5 - 0.5 * (TAU * hz * context.time).cos()) as f32;
+                let sample = texture.sample_rgba(uv, context, rng);
+                sample.truncate().lerp(*color, t).extend(sample.w)
+            }
+            Texture::Cutout(texture, key) => {
+                // filtering would blend the key into the edges so that they no longer match it
+                let color = texture.sample_rgba(uv, &SampleContext { filter: Filter::Nearest, ..*context }, rng);
+                // keys are compared at 8 bit precision
+                if (color.truncate() - *key).abs().max_element() < 0.5 / 255.0 {
+                    Vec4::ZERO
+                } else {
+                    color
+                }
             }
-            Texture::Glitch(amount) => Vec3::splat(rng.random::<f32>().powi(3) * (*amount) as f32)
+        }
+    }
+    /// whether every texel has full alpha
+    pub fn is_opaque(&self) -> bool {
+        match self {
+            Texture::Compound(texture, texture1, _) | Texture::Mask(texture, texture1, _) => texture.is_opaque() && texture1.is_opaque(),
+            Texture::Cutout(_, _) => false,
+            Texture::Flipbook(frames, _) => !frames.is_empty() && frames.iter().all(Texture::is_opaque),
+            Texture::Scroll(texture, _) | Texture::Pulse(texture, _, _) => texture.is_opaque(),
+            Texture::Stretch(image) | Texture::Repeat(image) => image.is_opaque(),
+            _ => true,
         }
     }
     pub fn contains_glitch(&self) -> bool {
         match self {
             Texture::Glitch(_) => true,
             Texture::Compound(texture, texture1, _) => texture.contains_glitch() || texture1.contains_glitch(),
+            Texture::Mask(a, b, mask) => a.contains_glitch() || b.contains_glitch() || mask.contains_glitch(),
+            Texture::Cutout(texture, _) | Texture::Scroll(texture, _) | Texture::Pulse(texture, _, _) => texture.contains_glitch(),
+            Texture::Flipbook(frames, _) => frames.iter().any(Texture::contains_glitch),
             _ => false,
         }
     }
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Where a [`Texture::Pattern`] is laid out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Space {
+    /// 0 to 1 across the surface, stretching with it
+    Uv,
+    /// in world units along the surface, so neighbouring surfaces line up
+    #[default]
+    World,
+}
+
+/// Procedural content, in grey or between the given colors. Sizes are in units of the pattern's [`Space`]
+/// and noise evolves at `rate` cells per second.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum Pattern {
+    /// blocky random values
+    Value {
+        cell: f64,
+        #[serde(default)]
+        rate: f64,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+    },
+    /// smooth clouds
+    Perlin {
+        cell: f64,
+        #[serde(default)]
+        rate: f64,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+    },
+    /// dark spots growing into bright cell edges
+    Worley {
+        cell: f64,
+        #[serde(default)]
+        rate: f64,
+    },
+    /// from one color to the other over `length`, going along `angle` in degrees from the u axis
+    Gradient {
+        from: Vec3,
+        to: Vec3,
+        #[serde(default)]
+        angle: f64,
+        #[serde(default = "default_length")]
+        length: f64,
+    },
+    /// alternating square tiles
+    Checker {
+        a: Vec3,
+        b: Vec3,
+        #[serde(default = "default_length")]
+        tile: f64,
+    },
+    /// rows of bricks, every other one shifted by half a brick, with mortar in between
+    Brick {
+        brick: Vec3,
+        mortar: Vec3,
+        #[serde(default = "default_brick_size")]
+        size: DVec2,
+        #[serde(default = "default_mortar_width")]
+        mortar_width: f64,
+    },
+}
+fn default_octaves() -> u32 {
+    1
+}
+fn default_length() -> f64 {
+    1.0
+}
+fn default_brick_size() -> DVec2 {
+    DVec2::new(0.5, 0.25)
+}
+fn default_mortar_width() -> f64 {
+    0.02
+}
+impl Pattern {
+    /// The color at `p` at `time` seconds.
+    pub fn sample(&self, p: DVec2, time: f64) -> Vec3 {
+        let noise = |cell: f64, rate: f64| (p / cell).extend(time * rate);
+        match *self {
+            Pattern::Value { cell, rate, octaves } => Vec3::splat(noise::fractal(noise::value, noise(cell, rate), octaves) as f32),
+            Pattern::Perlin { cell, rate, octaves } => Vec3::splat(noise::fractal(noise::perlin, noise(cell, rate), octaves) as f32),
+            Pattern::Worley { cell, rate } => Vec3::splat(noise::worley(noise(cell, rate)) as f32),
+            Pattern::Gradient { from, to, angle, length } => {
+                let t = (p.dot(DVec2::from_angle(angle.to_radians())) / length).clamp(0.0, 1.0);
+                from.lerp(to, t as f32)
+            }
+            Pattern::Checker { a, b, tile } => {
+                let tile = (p / tile).floor();
+                if (tile.x + tile.y).rem_euclid(2.0) == 0.0 { a } else { b }
+            }
+            Pattern::Brick { brick, mortar, size, mortar_width } => {
+                let row = (p.y / size.y).floor();
+                let shift = if row.rem_euclid(2.0) == 0.0 { 0.0 } else { size.x / 2.0 };
+                let local = DVec2::new(p.x + shift, p.y).rem_euclid(size);
+                if local.x < mortar_width || local.y < mortar_width { mortar } else { brick }
+            }
+        }
+    }
+}
+
+/// How bright a color looks, weighting green the most
+pub fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// How the second texture of a [`Texture::Compound`] is laid over the first.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
 pub enum BlendMode {
     Multiply,
     Add,
     #[default]
-    Mean
+    Mean,
+    /// the inverse of multiplying the inverses, brightens without going over 1
+    Screen,
+    /// multiplies the dark parts of the first texture and screens the bright parts, keeping its contrast
+    Overlay,
+    /// the second from the first, stopping at black
+    Subtract,
+    Min,
+    Max,
+    Difference,
+    /// 0 is all the first texture and 1 all the second
+    Lerp(f32),
 }
 impl BlendMode {
     pub fn blend(&self, a: Vec3, b: Vec3) -> Vec3 {
@@ -56,6 +265,13 @@ impl BlendMode {
             BlendMode::Multiply => a * b,
             BlendMode::Add => a + b,
             BlendMode::Mean => (a + b) / 2.0,
+            BlendMode::Screen => 1.0 - (1.0 - a) * (1.0 - b),
+            BlendMode::Overlay => Vec3::select(a.cmplt(Vec3::splat(0.5)), 2.0 * a * b, 1.0 - 2.0 * (1.0 - a) * (1.0 - b)),
+            BlendMode::Subtract => (a - b).max(Vec3::ZERO),
+            BlendMode::Min => a.min(b),
+            BlendMode::Max => a.max(b),
+            BlendMode::Difference => (a - b).abs(),
+            BlendMode::Lerp(t) => a.lerp(b, *t),
         }
     }
-}
\ No newline at end of file
+}